@@ -1,5 +1,11 @@
 use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::convert::SourceMap;
 
 /// Common arguments of compile, watch, and query.
 #[derive(Debug, Clone)]
@@ -24,7 +30,65 @@ pub struct SharedArgs {
   /// Arguments related to storage of packages in the system
   pub package_storage_args: PackageStorageArgs,
 
-  pub output: PathBuf,
+  /// The formats to export and the output file of each.
+  pub outputs: Vec<(OutputFormat, PathBuf)>,
+
+  /// Which pages to export. When unspecified, all document pages are exported.
+  pub pages: Option<Vec<Pages>>,
+
+  /// One (or multiple) PDF standards that Typst will enforce conformance with.
+  pub pdf_standard: Vec<PdfStandard>,
+
+  /// The PPI (pixels per inch) to use for PNG export.
+  pub ppi: f32,
+
+  /// Fail when compilation produced warnings
+  pub deny_warnings: bool,
+
+  /// Where the chapters of the input were converted from
+  pub source_map: SourceMap,
+}
+
+/// Which format to use for the generated output file.
+#[derive(Debug, Default, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase", try_from = "String")]
+pub enum OutputFormat {
+  #[default]
+  Pdf,
+  Png,
+  Svg,
+}
+
+/// Rejects `html` while reading the config, before the book is converted, as
+/// the typst version this backend is built with has no HTML export.
+impl TryFrom<String> for OutputFormat {
+  type Error = String;
+
+  fn try_from(format: String) -> Result<Self, Self::Error> {
+    match format.as_str() {
+      "pdf" => Ok(OutputFormat::Pdf),
+      "png" => Ok(OutputFormat::Png),
+      "svg" => Ok(OutputFormat::Svg),
+      "html" => {
+        Err("html format is not supported, HTML export requires typst 0.13 or later".into())
+      }
+      _ => Err(format!(
+        "unknown format `{}`, expected `pdf`, `png` or `svg`",
+        format
+      )),
+    }
+  }
+}
+
+impl OutputFormat {
+  /// The file extension of the output format.
+  pub fn extension(self) -> &'static str {
+    match self {
+      OutputFormat::Pdf => "pdf",
+      OutputFormat::Png => "png",
+      OutputFormat::Svg => "svg",
+    }
+  }
 }
 
 /// Which format to use for diagnostics.
@@ -34,6 +98,18 @@ pub enum DiagnosticFormat {
   Short,
 }
 
+/// A PDF standard that Typst can enforce conformance with.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[allow(non_camel_case_types)]
+pub enum PdfStandard {
+  /// PDF 1.7.
+  #[serde(rename = "1.7")]
+  V_1_7,
+  /// PDF/A-2b.
+  #[serde(rename = "a-2b")]
+  A_2b,
+}
+
 /// An input that is either stdin or a real path.
 #[derive(Debug, Clone)]
 pub enum Input {
@@ -52,10 +128,13 @@ pub struct PackageStorageArgs {
 
   /// Custom path to package cache, defaults to system-dependent location
   pub package_cache_path: Option<PathBuf>,
+
+  /// Only use packages already on disk, never download them
+  pub offline: bool,
 }
 
 /// Common arguments to customize available fonts
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct FontArgs {
   /// Adds additional directories that are recursively searched for fonts
   ///
@@ -66,4 +145,88 @@ pub struct FontArgs {
   /// Ensures system fonts won't be searched, unless explicitly included via
   /// `--font-path`
   pub ignore_system_fonts: bool,
+
+  /// Ensures fonts embedded into the binary won't be used
+  #[cfg_attr(not(feature = "embed-fonts"), allow(dead_code))]
+  pub ignore_embedded_fonts: bool,
+}
+
+/// Implements parsing of page ranges (`1-3`, `4`, `5-`, `-2`), used by the
+/// `pages` option, through the `FromStr` trait.
+#[derive(Debug, Clone)]
+pub struct Pages(pub RangeInclusive<Option<NonZeroUsize>>);
+
+impl FromStr for Pages {
+  type Err = &'static str;
+
+  fn from_str(value: &str) -> Result<Self, Self::Err> {
+    match value
+      .split('-')
+      .map(str::trim)
+      .collect::<Vec<_>>()
+      .as_slice()
+    {
+      [] | [""] => Err("page export range must not be empty"),
+      [single_page] => {
+        let page_number = parse_page_number(single_page)?;
+        Ok(Pages(Some(page_number)..=Some(page_number)))
+      }
+      ["", ""] => Err("page export range must have start or end"),
+      [start, ""] => Ok(Pages(Some(parse_page_number(start)?)..=None)),
+      ["", end] => Ok(Pages(None..=Some(parse_page_number(end)?))),
+      [start, end] => {
+        let start = parse_page_number(start)?;
+        let end = parse_page_number(end)?;
+        if start > end {
+          Err("page export range must end at a page after the start")
+        } else {
+          Ok(Pages(Some(start)..=Some(end)))
+        }
+      }
+      [_, _, _, ..] => Err("page export range must have a single hyphen"),
+    }
+  }
+}
+
+/// Parses a single page number.
+fn parse_page_number(value: &str) -> Result<NonZeroUsize, &'static str> {
+  if value == "0" {
+    Err("page numbers start at one")
+  } else {
+    NonZeroUsize::from_str(value).map_err(|_| "not a valid page number")
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::Config;
+
+  fn output_config(toml: &str) -> Result<Option<Config>, mdbook::errors::Error> {
+    toml
+      .parse::<mdbook::Config>()
+      .unwrap()
+      .get_deserialized_opt("output.typst-pdf")
+  }
+
+  #[test]
+  fn output_formats() {
+    let cfg = output_config("[output.typst-pdf]\nformats = [\"pdf\", \"svg\"]")
+      .unwrap()
+      .unwrap();
+
+    assert_eq!(
+      cfg.output_formats().collect::<Vec<_>>(),
+      [OutputFormat::Pdf, OutputFormat::Svg]
+    );
+  }
+
+  #[test]
+  fn html_format_rejected() {
+    let err = output_config("[output.typst-pdf]\npdf = false\nformat = \"html\"").unwrap_err();
+
+    assert!(format!("{:?}", err).contains("html format is not supported"));
+    assert!(output_config("[output.typst-pdf]\nformats = [\"pdf\", \"html\"]").is_err());
+    assert!(output_config("[output.typst-pdf]\nformat = \"docx\"").is_err());
+  }
 }
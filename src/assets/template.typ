@@ -1,6 +1,14 @@
+#set document(
+  title: [MDBOOK_TYPST_PDF_TITLE],
+  author: MDBOOK_TYPST_PDF_AUTHORS,
+  keywords: MDBOOK_TYPST_PDF_KEYWORDS,
+)
+
 #set text(
-  lang: "zh",
+  lang: MDBOOK_TYPST_PDF_LANG,
+  region: MDBOOK_TYPST_PDF_REGION,
   font: (
+    MDBOOK_TYPST_PDF_FONT
     "Noto Sans",
     "Noto Sans SC",
     "Noto Sans KR",
@@ -12,7 +20,11 @@
   ),
 )
 
-#show link: underline
+MDBOOK_TYPST_PDF_LINK_STYLE
+
+MDBOOK_TYPST_PDF_HIGHLIGHT_THEME
+
+MDBOOK_TYPST_PDF_CODE_FONT
 
 #show raw.where(block: true): block.with(
   width: 100%,
@@ -29,27 +41,28 @@
 )
 
 #set page(
-  header: context {
-    if counter(page).get().first() > 1 [
-      MDBOOK_TYPST_PDF_TITLE
-    ]
-  },
-  footer: context {
-    if counter(page).get().first() > 1 [
-      #counter(page).display(
-        "1/1",
-        both: true,
-      )
-    ]
-  },
+  paper: "MDBOOK_TYPST_PDF_PAPER",
+  flipped: MDBOOK_TYPST_PDF_FLIPPED,
+  margin: MDBOOK_TYPST_PDF_MARGIN,
+  header: MDBOOK_TYPST_PDF_HEADER,
+  footer: MDBOOK_TYPST_PDF_FOOTER,
 )
 
+MDBOOK_TYPST_PDF_COVER_IMAGE
+
 #align(center, text(17pt)[
   *MDBOOK_TYPST_PDF_TITLE*
 ])
 
+MDBOOK_TYPST_PDF_COVER_SUBTITLE
+
+MDBOOK_TYPST_PDF_COVER_AUTHOR
+
+MDBOOK_TYPST_PDF_COVER_DATE
+
 #pagebreak()
-#outline(depth: 2, indent: 1em)
-#pagebreak()
+MDBOOK_TYPST_PDF_TOC
 
 /**** MDBOOK_TYPST_PDF_PLACEHOLDER ****/
+
+MDBOOK_TYPST_PDF_BIBLIOGRAPHY
@@ -1,35 +1,198 @@
-use anyhow::anyhow;
+use anyhow::{anyhow, Context};
 use html5ever::parse_document;
 use html5ever::tendril::TendrilSink;
-use markup5ever_rcdom::{NodeData, RcDom};
+use image::codecs::jpeg::{JpegEncoder, PixelDensity};
+use image::imageops::FilterType;
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+use mdbook::book::Chapter;
 use mdbook::renderer::RenderContext;
 use mdbook::BookItem;
-use pulldown_cmark::{Alignment, CodeBlockKind, Event, Options, Parser, Tag, TagEnd};
+use parking_lot::Mutex;
+use percent_encoding::percent_decode_str;
+use pulldown_cmark::{
+  Alignment, BlockQuoteKind, BrokenLink, CodeBlockKind, CowStr, Event, LinkType, Options, Parser,
+  Tag, TagEnd, TextMergeWithOffset,
+};
+use rayon::prelude::*;
 use regex::Regex;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write;
 use std::fs;
+use std::io::{self, Write as _};
+use std::iter::Peekable;
+use std::ops::{Range, RangeInclusive};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::{Chars, FromStr};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::OnceLock;
+use typst::layout::Paper;
+use typst::text::{Lang, RawElem};
+use typst::visualize::{RasterFormat, RasterImage};
 
-use crate::Config;
+use crate::download::{self, PrintDownload};
+use crate::terminal::Status;
+use crate::{Config, DraftChapters, EmptyChapters, Orientation, TableColumns};
+
+/// Where the template receives the converted chapters, unless it has
+/// `BODY_PLACEHOLDER`.
+pub const CONTENT_PLACEHOLDER: &str = "/**** MDBOOK_TYPST_PDF_PLACEHOLDER ****/";
+
+/// Where the template receives the numbered chapters.
+pub const BODY_PLACEHOLDER: &str = "/**** MDBOOK_TYPST_PDF_BODY ****/";
+
+/// Where the template receives the prefix chapters, before the body if absent.
+pub const FRONTMATTER_PLACEHOLDER: &str = "/**** MDBOOK_TYPST_PDF_FRONTMATTER ****/";
+
+/// Where the template receives the suffix chapters, after the body if absent.
+pub const BACKMATTER_PLACEHOLDER: &str = "/**** MDBOOK_TYPST_PDF_BACKMATTER ****/";
 
 static EMAIL_REGEX: OnceLock<Regex> = OnceLock::new();
+static LENGTH_REGEX: OnceLock<Regex> = OnceLock::new();
+static BARE_URL_REGEX: OnceLock<Regex> = OnceLock::new();
+static DIRECTIVE_REGEX: OnceLock<Regex> = OnceLock::new();
+static FIGURE_ID_REGEX: OnceLock<Regex> = OnceLock::new();
+static RAW_LANGS: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// The names typst math knows without a `#`, like `alpha`, `sum` and `sin`.
+static MATH_NAMES: OnceLock<HashSet<String>> = OnceLock::new();
+
+/// Code block languages typst does not know under these names.
+const LANG_ALIASES: &[(&str, &str)] = &[("sh", "bash"), ("shell", "bash"), ("zsh", "bash")];
 
 #[derive(Debug, PartialEq)]
 pub enum EventType {
   CodeBlockIndented,
   CodeBlockFenced(String),
+  CodeBlockRender(String),
+  CodeBlockPassthrough,
   List,
-  NumberedList,
+  NumberedList(u64),
   TableHead,
+  TaskListItem,
   Image,
   Heading,
 }
 
+/// Where the chapters of the generated typst come from, to point compile
+/// diagnostics at the Markdown.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+  chapters: Vec<MappedChapter>,
+}
+
+impl SourceMap {
+  /// The name, source path and Markdown line of the chapter a byte offset of
+  /// the typst was converted from.
+  pub fn locate(&self, offset: usize) -> Option<(&str, &Path, usize)> {
+    let chapter = self
+      .chapters
+      .iter()
+      .find(|chapter| chapter.range.contains(&offset))?;
+
+    let relative = offset - chapter.range.start;
+
+    let line = match chapter
+      .lines
+      .partition_point(|(typst, _)| *typst <= relative)
+    {
+      0 => 1,
+      index => chapter.lines[index - 1].1,
+    };
+
+    Some((&chapter.name, &chapter.path, line))
+  }
+}
+
+/// A chapter's content in the generated typst.
+#[derive(Debug, Clone)]
+struct MappedChapter {
+  name: String,
+  path: PathBuf,
+  range: Range<usize>,
+  /// Offsets into the content and the Markdown lines they were converted from.
+  lines: Vec<(usize, usize)>,
+}
+
+/// Typst and the chapters converted into it.
+#[derive(Default)]
+struct MappedTypst {
+  typst: String,
+  chapters: Vec<MappedChapter>,
+}
+
+/// What is written at a placeholder of the template.
+enum Part {
+  Typst(String),
+  /// A range of the book items, converted while writing.
+  Chapters(Range<usize>),
+}
+
+/// Writes the typst and keeps the source map of the chapters written so far.
+struct TypstWriter<W: io::Write> {
+  writer: W,
+  offset: usize,
+  chapters: Vec<MappedChapter>,
+}
+
+impl<W: io::Write> TypstWriter<W> {
+  fn new(writer: W) -> Self {
+    TypstWriter {
+      writer,
+      offset: 0,
+      chapters: Vec::new(),
+    }
+  }
+
+  fn write_str(&mut self, typst: &str) -> io::Result<()> {
+    self.writer.write_all(typst.as_bytes())?;
+    self.offset += typst.len();
+
+    Ok(())
+  }
+
+  fn write(&mut self, typst: MappedTypst) -> io::Result<()> {
+    let offset = self.offset;
+
+    self
+      .chapters
+      .extend(typst.chapters.into_iter().map(|mut chapter| {
+        chapter.range = chapter.range.start + offset..chapter.range.end + offset;
+        chapter
+      }));
+
+    self.write_str(&typst.typst)
+  }
+
+  fn finish(mut self) -> Result<SourceMap, anyhow::Error> {
+    self.writer.flush()?;
+
+    Ok(SourceMap {
+      chapters: self.chapters,
+    })
+  }
+}
+
+impl From<String> for MappedTypst {
+  fn from(typst: String) -> Self {
+    MappedTypst {
+      typst,
+      chapters: Vec::new(),
+    }
+  }
+}
+
+/// Convert the book into the template and write the typst to `writer`.
+///
+/// The chapters are written as they are converted, so the whole book is never
+/// held in memory at once.
 pub fn convert_typst(
   ctx: &RenderContext,
   cfg: &Config,
   template: &str,
-) -> Result<String, anyhow::Error> {
+  writer: impl io::Write,
+) -> Result<SourceMap, anyhow::Error> {
   let title = ctx
     .config
     .book
@@ -37,435 +200,4082 @@ pub fn convert_typst(
     .as_ref()
     .ok_or(anyhow!("title not found"))?;
 
-  let mut output_template = template.to_owned().replace("MDBOOK_TYPST_PDF_TITLE", title);
+  let paper = match &cfg.paper {
+    Some(paper) if Paper::from_str(paper).is_ok() => paper.to_lowercase(),
+    Some(paper) => {
+      tracing::warn!("Unknown paper size `{}`, falling back to `a4`", paper);
 
-  let mut typst_str = String::new();
+      "a4".to_string()
+    }
+    None => "a4".to_string(),
+  };
 
-  for item in ctx.book.iter() {
-    writeln!(typst_str, "{}", convert_book_item(ctx, cfg, item)?)?;
-  }
+  let (lang, region) = convert_language(ctx.config.book.language.as_deref());
 
-  let placeholder = "/**** MDBOOK_TYPST_PDF_PLACEHOLDER ****/\n";
-  let target = output_template.find(placeholder).unwrap_or_default() + placeholder.len();
+  let flipped = cfg.orientation == Orientation::Landscape;
 
-  output_template.insert_str(target, &typst_str);
+  let margin = convert_margin(cfg)?;
 
-  Ok(output_template)
-}
+  // Fail before compilation on invalid table styles.
+  convert_table_style(cfg)?;
 
-fn convert_book_item(
-  ctx: &RenderContext,
-  cfg: &Config,
-  item: &BookItem,
-) -> Result<String, anyhow::Error> {
-  let mut book_item_str = String::new();
+  let font = cfg
+    .font_families()
+    .map(|f| format!("{},", typst_string(f)))
+    .collect::<Vec<String>>()
+    .join(" ");
 
-  if let BookItem::Chapter(ref ch) = *item {
-    let label_path = ch
-      .source_path
-      .to_owned()
-      .ok_or(anyhow!("source_path not found"))?;
-
-    let label = label_path
-      .as_path()
-      .file_name()
-      .and_then(|f| f.to_str())
-      .and_then(|f| f.split('.').next())
-      .ok_or(anyhow!("label not found"))?;
-
-    let invisible_heading = if let Some(number) = &ch.number {
-      if cfg.section_number {
-        format!(
-          "#{{\n  show heading: none\n  set text(size: 0pt, fill: white)\n  heading(numbering: none, level: {}, outlined: true)[#\"{} {}\"]\n}} <{}.html>",
-          number.len(),
-          number,
-          ch.name,
-          label,
-        )
-      } else {
-        format!(
-          "#{{\n  show heading: none\n  set text(size: 0pt, fill: white)\n  heading(numbering: none, level: {}, outlined: true)[{}]\n}} <{}.html>",
-          number.len(),
-          ch.name,
-          label
-        )
+  let toc = if cfg.toc {
+    format!(
+      "#outline(depth: {}, indent: 1em)\n#pagebreak()",
+      cfg.toc_depth
+    )
+  } else {
+    String::new()
+  };
+
+  let bibliography = match &cfg.bibliography {
+    None => String::new(),
+    Some(bibliography) => {
+      let src_path = ctx.root.join(bibliography);
+
+      let bibliography_filename = src_path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .ok_or(anyhow!("invalid bibliography `{}`", bibliography))?;
+
+      fs::create_dir_all(&ctx.destination)?;
+
+      fs::copy(&src_path, ctx.destination.join(bibliography_filename))
+        .map_err(|err| anyhow!("failed to copy bibliography `{}`: {}", bibliography, err))?;
+
+      match &cfg.bibliography_style {
+        Some(style) => format!(
+          "#bibliography({}, style: {})",
+          typst_string(bibliography_filename),
+          typst_string(style)
+        ),
+        None => format!("#bibliography({})", typst_string(bibliography_filename)),
       }
-    } else {
+    }
+  };
+
+  let highlight_theme = match cfg.highlight_theme.as_deref() {
+    None => String::new(),
+    Some("none") => "#set raw(theme: none)".to_string(),
+    Some(theme) => {
+      let src_path = ctx.root.join(theme);
+
+      let theme_filename = src_path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .ok_or(anyhow!("invalid highlight theme `{}`", theme))?;
+
+      fs::create_dir_all(&ctx.destination)?;
+
+      fs::copy(&src_path, ctx.destination.join(theme_filename))
+        .map_err(|err| anyhow!("failed to copy highlight theme `{}`: {}", theme, err))?;
+
+      format!("#set raw(theme: {})", typst_string(theme_filename))
+    }
+  };
+
+  let cover_image = match &cfg.cover.image {
+    None => String::new(),
+    Some(image) => {
+      let src_path = ctx.root.join(image);
+
+      let image_filename = src_path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .ok_or(anyhow!("invalid cover image `{}`", image))?;
+
+      fs::create_dir_all(&ctx.destination)?;
+
+      fs::copy(&src_path, ctx.destination.join(image_filename))
+        .map_err(|err| anyhow!("failed to copy cover image `{}`: {}", image, err))?;
+
       format!(
-        "#{{\n  show heading: none\n  set text(size: 0pt, fill: white)\n  heading(numbering: none, level: 1, outlined: true)[{}]\n}} <{}.html>",
-        ch.name, label,
+        "#align(center, image({}, width: 60%))",
+        typst_string(image_filename)
       )
-    };
-
-    if cfg.chapter_no_pagebreak {
-      writeln!(
-        book_item_str,
-        "{}",
-        convert_content(ctx, &ch.content, label, &invisible_heading)?
-      )?;
-    } else {
-      writeln!(
-        book_item_str,
-        "{}#pagebreak(weak: true)",
-        convert_content(ctx, &ch.content, label, &invisible_heading)?
-      )?;
     }
-  }
+  };
 
-  Ok(book_item_str)
-}
+  let cover_text = |text: Option<&String>, size: &str| {
+    text
+      .as_ref()
+      .map(|text| format!("#align(center, text({})[{}])", size, escape_text(text)))
+      .unwrap_or_default()
+  };
 
-fn convert_content(
-  ctx: &RenderContext,
-  content: &str,
-  label: &str,
-  invisible_heading: &str,
-) -> Result<String, anyhow::Error> {
-  let mut content_str = String::new();
+  let cover_subtitle = cover_text(cfg.cover.subtitle.as_ref(), "14pt");
 
-  let mut heading = String::new();
+  let cover_author = cover_text(cfg.cover.author.as_ref(), "12pt");
 
-  let mut writen_invisible_heading = false;
+  let date = convert_date(cfg)?;
 
-  let options = Options::ENABLE_SMART_PUNCTUATION
-    | Options::ENABLE_STRIKETHROUGH
-    | Options::ENABLE_FOOTNOTES
-    | Options::ENABLE_TASKLISTS
-    | Options::ENABLE_TABLES;
+  let cover_date = cover_text(cfg.cover.date.as_ref().or(date.as_ref()), "12pt");
 
-  let parser = Parser::new_ext(content, options);
+  let header = if cfg.header {
+    convert_page_format(cfg.header_format.as_deref().unwrap_or("{title}"), title)
+  } else {
+    "none".to_string()
+  };
 
-  let mut event_stack = Vec::new();
+  let footer = if cfg.footer {
+    convert_page_format(
+      cfg.footer_format.as_deref().unwrap_or("{page}/{total}"),
+      title,
+    )
+  } else {
+    "none".to_string()
+  };
 
-  for event in parser {
-    match event {
-      Event::Start(Tag::Heading { level, .. }) => {
-        event_stack.push(EventType::Heading);
+  let link_style = convert_link_style(cfg)?;
 
-        heading.clear();
+  let code_font = convert_code_font(cfg)?;
 
-        let level_usize: usize = level as usize;
+  let authors = typst_array(&ctx.config.book.authors);
 
-        write!(
-          content_str,
-          "#heading(level: {}, outlined: false)[",
-          level_usize,
-        )?;
-      }
-      Event::End(TagEnd::Heading(_)) => {
-        event_stack.pop();
+  let keywords = typst_array(&cfg.keywords);
 
-        writeln!(
-          content_str,
-          "] <{}.html-{}>",
-          label,
-          mdbook::utils::normalize_id(&heading)
-        )?;
+  let output_template = template
+    .to_owned()
+    .replace("MDBOOK_TYPST_PDF_TITLE", title)
+    .replace("MDBOOK_TYPST_PDF_LANG", &lang)
+    .replace("MDBOOK_TYPST_PDF_REGION", &region)
+    .replace("MDBOOK_TYPST_PDF_PAPER", &paper)
+    .replace("MDBOOK_TYPST_PDF_FLIPPED", &flipped.to_string())
+    .replace("MDBOOK_TYPST_PDF_MARGIN", &margin)
+    .replace("MDBOOK_TYPST_PDF_FONT", &font)
+    .replace("MDBOOK_TYPST_PDF_TOC", &toc)
+    .replace("MDBOOK_TYPST_PDF_HEADER", &header)
+    .replace("MDBOOK_TYPST_PDF_FOOTER", &footer)
+    .replace("MDBOOK_TYPST_PDF_LINK_STYLE", &link_style)
+    .replace("MDBOOK_TYPST_PDF_AUTHORS", &authors)
+    .replace("MDBOOK_TYPST_PDF_KEYWORDS", &keywords)
+    .replace("MDBOOK_TYPST_PDF_BIBLIOGRAPHY", &bibliography)
+    .replace("MDBOOK_TYPST_PDF_HIGHLIGHT_THEME", &highlight_theme)
+    .replace("MDBOOK_TYPST_PDF_CODE_FONT", &code_font)
+    .replace("MDBOOK_TYPST_PDF_COVER_IMAGE", &cover_image)
+    .replace("MDBOOK_TYPST_PDF_COVER_SUBTITLE", &cover_subtitle)
+    .replace("MDBOOK_TYPST_PDF_COVER_AUTHOR", &cover_author)
+    .replace("MDBOOK_TYPST_PDF_COVER_DATE", &cover_date)
+    .replace(
+      "MDBOOK_TYPST_PDF_DATE",
+      &date.as_deref().map_or("none".to_string(), typst_string),
+    );
 
-        if !writen_invisible_heading {
-          writeln!(content_str, "{}", invisible_heading)?;
+  // mdBook prefix and suffix chapters are the unnumbered chapters before the
+  // first and after the last numbered chapter or part, they have no nested
+  // chapters so they are also the first and last items of the book.
+  let is_main = |item: &BookItem| match item {
+    BookItem::Chapter(ch) => ch.number.is_some(),
+    BookItem::PartTitle(_) => true,
+    BookItem::Separator => false,
+  };
 
-          writen_invisible_heading = true;
-        }
-      }
-      Event::Start(Tag::Emphasis) => write!(content_str, "_")?,
-      Event::End(TagEnd::Emphasis) => write!(content_str, "_")?,
-      Event::Start(Tag::Strong) => write!(content_str, "*")?,
-      Event::End(TagEnd::Strong) => write!(content_str, "*")?,
-      Event::Start(Tag::BlockQuote(_)) => write!(content_str, "#quote(block: true)[")?,
-      Event::End(TagEnd::BlockQuote(_)) => writeln!(content_str, "]")?,
-      Event::Start(Tag::List(None)) => {
-        event_stack.push(EventType::List);
-      }
-      Event::Start(Tag::List(Some(_))) => {
-        event_stack.push(EventType::NumberedList);
-      }
-      Event::End(TagEnd::List(_)) => {
-        event_stack.pop();
-      }
-      Event::Start(Tag::Item) => match event_stack.last() {
-        Some(EventType::List) => write!(content_str, "- ")?,
-        Some(EventType::NumberedList) => write!(content_str, "+ ")?,
-        _ => write!(content_str, "- ")?,
-      },
-      Event::End(TagEnd::Item) => writeln!(content_str)?,
-      Event::Start(Tag::Paragraph) => (),
-      Event::End(TagEnd::Paragraph) => write!(content_str, "\n\n")?,
-      Event::Start(Tag::Link { dest_url, .. }) => {
-        let email_regex: &Regex = EMAIL_REGEX
-          .get_or_init(|| Regex::new(r"(?i)^\w+([\.-]?\w+)*@\w+([\.-]?\w+)*(.\w{2,3})+$").unwrap());
-
-        if dest_url.starts_with("http://") || dest_url.starts_with("https://") {
-          write!(content_str, "#link(\"{}\")[", dest_url)?
-        } else if email_regex.is_match(&dest_url) {
-          write!(content_str, "#link(\"mailto:{}\")[", dest_url)?
-        } else if dest_url.starts_with('#') {
-          write!(
-            content_str,
-            "#link(<{}.html{}>)[",
-            label,
-            dest_url.replace('#', "-")
-          )?
-        } else {
-          write!(content_str, "#link(<{}>)[", dest_url.replace('#', "-"))?
-        }
-      }
-      Event::End(TagEnd::Link) => write!(content_str, "]")?,
-      Event::Start(Tag::Table(align)) => {
-        let typst_align = align
-          .iter()
-          .map(|a| match a {
-            Alignment::None => "auto",
-            Alignment::Left => "left",
-            Alignment::Center => "center",
-            Alignment::Right => "right",
-          })
-          .collect::<Vec<&str>>()
-          .join(", ");
+  let sections = &ctx.book.sections;
 
-        writeln!(
-          content_str,
-          "#table(\n  columns: {},\n  inset: 10pt,\n  align: ({}),\n  ",
-          align.len(),
-          typst_align
-        )?
-      }
-      Event::End(TagEnd::Table) => writeln!(content_str, ")")?,
-      Event::Start(Tag::TableHead) => {
-        event_stack.push(EventType::TableHead);
-      }
-      Event::End(TagEnd::TableHead) => {
-        event_stack.pop();
-      }
-      Event::Start(Tag::TableRow) => (),
-      Event::End(TagEnd::TableRow) => (),
-      Event::Start(Tag::TableCell) => write!(content_str, "[")?,
-      Event::End(TagEnd::TableCell) => writeln!(content_str, "],")?,
-      Event::Start(Tag::Image { dest_url, .. }) => {
-        event_stack.push(EventType::Image);
+  let (prefix_items, suffix_items) = match (
+    sections.iter().position(is_main),
+    sections.iter().rposition(is_main),
+  ) {
+    (Some(first), Some(last)) => (first, sections.len() - last - 1),
+    _ => (0, 0),
+  };
 
-        let src_path = ctx
-          .root
-          .join(
-            ctx
-              .config
-              .book
-              .src
-              .to_str()
-              .ok_or(anyhow!("src not found"))?,
-          )
-          .join(dest_url.to_string());
-        let dest_path = ctx.destination.join(dest_url.to_string());
+  let items = ctx.book.iter().collect::<Vec<&BookItem>>();
 
-        let dest_dir = dest_path.parent().ok_or(anyhow!("destination not found"))?;
+  let body_start = prefix_items;
 
-        fs::create_dir_all(dest_dir)?;
+  let body_end = items.len() - suffix_items;
 
-        if !dest_path.exists() {
-          fs::copy(src_path, dest_path)?;
-        }
+  let mut frontmatter = vec![];
 
-        write!(content_str, "#figure(\n  image(\"{}\")\n)", dest_url)?
-      }
-      Event::End(TagEnd::Image) => {
-        event_stack.pop();
+  let mut body = vec![];
 
-        writeln!(content_str)?
-      }
-      Event::Start(Tag::CodeBlock(ref lang)) => match lang {
-        CodeBlockKind::Indented => {
-          event_stack.push(EventType::CodeBlockIndented);
+  // Prefix chapters are numbered with roman numerals, the body restarts at 1.
+  if cfg.front_matter_numbering && prefix_items > 0 {
+    frontmatter.push(Part::Typst(
+      "#set page(numbering: \"i\")\n#counter(page).update(1)\n".to_string(),
+    ));
 
-          writeln!(content_str, "````")?
-        }
-        CodeBlockKind::Fenced(lang) => {
-          event_stack.push(EventType::CodeBlockFenced(lang.to_string()));
+    body.push(Part::Typst(
+      "#set page(numbering: \"1\")\n#counter(page).update(1)\n".to_string(),
+    ));
+  }
 
-          let langs: Vec<&str> = lang.split(',').collect();
+  // Only the chapters are set in columns, not the title page and table of
+  // contents before them.
+  if cfg.columns > 1 {
+    let columns = format!("#set page(columns: {})\n", cfg.columns);
 
-          if !langs.is_empty() {
-            let mut ferris_prefix = "".to_string();
+    if prefix_items > 0 {
+      frontmatter.push(Part::Typst(columns.clone()));
+    }
 
-            for l in langs.iter().skip(1) {
-              match l {
-                &"does_not_compile" | &"not_desired_behavior" | &"panics" => {
-                  ferris_prefix = "#columns(1)[\n".to_string();
-                }
-                _ => (),
-              }
-            }
+    body.push(Part::Typst(columns));
+  }
 
-            writeln!(content_str, "{}````{}", ferris_prefix, langs[0])?
-          } else {
-            writeln!(content_str, "````")?
-          }
-        }
-      },
-      Event::End(TagEnd::CodeBlock) => {
-        match event_stack.last() {
-          Some(EventType::CodeBlockIndented) => writeln!(content_str, "````")?,
-          Some(EventType::CodeBlockFenced(lang)) => {
-            let langs: Vec<&str> = lang.split(',').collect();
+  frontmatter.push(Part::Chapters(0..body_start));
 
-            if !langs.is_empty() {
-              let mut ferris_suffix = "".to_string();
+  body.push(Part::Chapters(body_start..body_end));
 
-              for l in langs.iter().skip(1) {
-                match l {
-                  &"does_not_compile" | &"not_desired_behavior" | &"panics" => {
-                    let ferris_src_path = format!("img/ferris/{}.svg", l);
+  let backmatter = vec![Part::Chapters(body_end..items.len())];
 
-                    let src_path = ctx
-                      .root
-                      .join(
-                        ctx
-                          .config
-                          .book
-                          .src
-                          .to_str()
-                          .ok_or(anyhow!("src not found"))?,
-                      )
-                      .join(&ferris_src_path);
+  let body_target = placeholder_target(&output_template, BODY_PLACEHOLDER)
+    .or_else(|| placeholder_target(&output_template, CONTENT_PLACEHOLDER))
+    .ok_or_else(|| {
+      anyhow!(
+        "template has no `{}`, add it on its own line where the chapters should go",
+        BODY_PLACEHOLDER
+      )
+    })?;
 
-                    let dest_path = ctx.destination.join(&ferris_src_path);
+  let mut regions = vec![];
 
-                    let dest_dir = dest_path.parent().ok_or(anyhow!("destination not found"))?;
+  // Without their own placeholders, front and back matter surround the body.
+  match placeholder_target(&output_template, FRONTMATTER_PLACEHOLDER) {
+    Some(target) => regions.push((target, frontmatter)),
+    None => {
+      frontmatter.append(&mut body);
 
-                    fs::create_dir_all(dest_dir)?;
+      body = frontmatter;
+    }
+  }
 
-                    if !dest_path.exists() {
-                      fs::copy(src_path, dest_path)?;
-                    }
+  match placeholder_target(&output_template, BACKMATTER_PLACEHOLDER) {
+    Some(target) => regions.push((target, backmatter)),
+    None => body.extend(backmatter),
+  }
 
-                    ferris_suffix = format!(
-                      "\n#place(\n  top + right,\n  figure(\n    image(\"{}\", width: 10%)\n  )\n)\n]",
-                      ferris_src_path
-                    );
-                  }
-                  _ => (),
-                }
-              }
+  regions.push((body_target, body));
 
-              writeln!(content_str, "````{}", ferris_suffix)?
-            } else {
-              writeln!(content_str, "````")?
-            }
-          }
-          _ => writeln!(content_str, "````")?,
-        }
+  regions.sort_by_key(|(target, _)| *target);
 
-        event_stack.pop();
-      }
-      Event::Code(t) => {
-        if event_stack.contains(&EventType::Heading) {
-          heading.push_str(&t);
-        }
+  let chapters = items
+    .iter()
+    .filter(|item| matches!(item, BookItem::Chapter(_)))
+    .count();
 
-        write!(
-          content_str,
-          r#"#raw("{}")"#,
-          t.replace('\\', r#"\\"#).replace('"', r#"\""#)
-        )?;
-      }
-      Event::Html(t) | Event::InlineHtml(t) => {
-        match t.trim() {
-          "<sup>" => {
-            write!(content_str, "#super[")?;
+  let figure_labels = figure_labels(ctx, cfg);
 
-            continue;
-          }
-          "</sup>" => {
-            write!(content_str, "]")?;
+  let status = Mutex::new(Status::new());
 
-            continue;
-          }
-          _ => (),
-        }
+  let converted = AtomicUsize::new(0);
 
-        let dom = parse_document(RcDom::default(), Default::default())
-          .from_utf8()
-          .read_from(&mut t.as_bytes())?;
+  // Chapters are converted in parallel in batches, so only a batch is held in
+  // memory before it is written.
+  let batch_size = rayon::current_num_threads() * 4;
 
-        let dom_children = &dom.document.children.borrow();
+  let mut writer = TypstWriter::new(writer);
 
-        if dom_children.len() > 0 && matches!(dom_children[0].data, NodeData::Element { .. }) {
-          let html_children = &dom_children[0].children.borrow();
+  let mut written = 0;
 
-          if html_children.len() > 1 {
-            let body_children = &html_children[1].children.borrow();
+  for (target, parts) in regions {
+    writer.write_str(&output_template[written..target])?;
 
-            if body_children.len() > 0 {
-              if let NodeData::Element { name, attrs, .. } = &body_children[0].data {
-                match name.local.as_ref() {
-                  "img" => {
-                    for attr in attrs.borrow().iter() {
-                      if attr.name.local.as_ref() == "src" {
-                        let attr_src_path = attr.value.to_string();
-
-                        let src_path = ctx
-                          .root
-                          .join(
-                            ctx
-                              .config
-                              .book
-                              .src
-                              .to_str()
-                              .ok_or(anyhow!("src not found"))?,
-                          )
-                          .join(&attr_src_path);
-                        let dest_path = ctx.destination.join(&attr_src_path);
-
-                        let dest_dir =
-                          dest_path.parent().ok_or(anyhow!("destination not found"))?;
-
-                        fs::create_dir_all(dest_dir)?;
-
-                        if !dest_path.exists() {
-                          fs::copy(src_path, dest_path)?;
-                        }
+    written = target;
 
-                        writeln!(content_str, "#figure(\n  image(\"{}\")\n)", attr_src_path)?
-                      }
-                    }
-                  }
-                  "span" => (),
-                  _ => (),
-                }
-              }
+    for part in parts {
+      let range = match part {
+        Part::Typst(typst) => {
+          writer.write_str(&typst)?;
+
+          continue;
+        }
+        Part::Chapters(range) => range,
+      };
+
+      for batch in items[range].chunks(batch_size) {
+        let converted_items = batch
+          .par_iter()
+          .map(|item| {
+            let item_typst = convert_book_item(ctx, cfg, item, &figure_labels)?;
+
+            if let BookItem::Chapter(ref ch) = **item {
+              let converted = converted.fetch_add(1, Ordering::Relaxed) + 1;
+
+              status.lock().update(format_args!(
+                "Converted chapter {}/{}: {}",
+                converted, chapters, ch.name
+              ));
             }
-          }
+
+            Ok(item_typst)
+          })
+          .collect::<Result<Vec<MappedTypst>, anyhow::Error>>()?;
+
+        for item_typst in converted_items {
+          writer.write(item_typst)?;
+
+          writer.write_str("\n")?;
         }
       }
-      Event::Text(t) => {
-        if event_stack.contains(&EventType::Heading) {
-          heading.push_str(&t);
-        }
+    }
+  }
 
-        match event_stack.last() {
-          Some(EventType::CodeBlockIndented) => write!(content_str, "{}", t)?,
-          Some(EventType::CodeBlockFenced(_)) => write!(content_str, "{}", t)?,
-          Some(EventType::TableHead) => write!(content_str, "*{}*", t)?,
-          Some(EventType::Image) => write!(content_str, "/* {} */", t)?,
-          _ => {
-            let mut transformed_text = String::with_capacity(t.len());
-            for ch in t.chars() {
-              match ch {
-                '#' | '$' | '`' | '*' | '_' | '<' | '>' | '@' => {
-                  transformed_text.push('\\');
-                  transformed_text.push(ch);
-                }
-                _ => transformed_text.push(ch),
-              }
-            }
+  writer.write_str(&output_template[written..])?;
 
-            write!(content_str, "{}", transformed_text)?
-          }
+  status.into_inner().finish();
+
+  writer.finish()
+}
+
+/// The `#show raw` rule of `code-font` and `code-font-size`, code falls back
+/// to typst's monospace font when `code-font` is not found.
+fn convert_code_font(cfg: &Config) -> Result<String, anyhow::Error> {
+  let mut args = Vec::new();
+
+  if let Some(font) = &cfg.code_font {
+    args.push(format!(
+      "font: ({}, \"DejaVu Sans Mono\")",
+      typst_string(font)
+    ));
+  }
+
+  if let Some(size) = &cfg.code_font_size {
+    if !length_regex().is_match(size) || size.ends_with('%') {
+      return Err(anyhow!("invalid code-font-size `{}`", size));
+    }
+
+    args.push(format!("size: {}", size));
+  }
+
+  if args.is_empty() {
+    return Ok(String::new());
+  }
+
+  Ok(format!("#show raw: set text({})", args.join(", ")))
+}
+
+/// The `date` formatted with `date-format`, `auto` is the creation timestamp
+/// or today. Other dates than `YYYY-MM-DD` are used as written.
+fn convert_date(cfg: &Config) -> Result<Option<String>, anyhow::Error> {
+  let date = match cfg.date.as_deref() {
+    None => return Ok(None),
+    Some("auto") => crate::creation_timestamp(cfg)?
+      .unwrap_or_else(chrono::Utc::now)
+      .date_naive(),
+    Some(date) => match chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+      Ok(date) => date,
+      Err(_) => return Ok(Some(date.to_string())),
+    },
+  };
+
+  let format = cfg.date_format.as_deref().unwrap_or("%Y-%m-%d");
+
+  let mut formatted = String::new();
+
+  write!(formatted, "{}", date.format(format))
+    .map_err(|_| anyhow!("invalid date-format `{}`", format))?;
+
+  Ok(Some(formatted))
+}
+
+/// The byte offset of the line after a placeholder in the template.
+fn placeholder_target(template: &str, placeholder: &str) -> Option<usize> {
+  let end = template.find(placeholder)? + placeholder.len();
+
+  Some(
+    template[end..]
+      .find('\n')
+      .map_or(template.len(), |newline| end + newline + 1),
+  )
+}
+
+/// Convert the `book.language` tag like `zh` or `pt-BR` into typst text `lang`
+/// and `region` values.
+///
+/// The primary subtag must be a two or three letter ISO 639 code, otherwise
+/// `en` is used. Script subtags like `Hans` are ignored.
+fn convert_language(language: Option<&str>) -> (String, String) {
+  let language = language.unwrap_or("en");
+
+  let mut subtags = language.split(['-', '_']);
+
+  let lang = match subtags.next() {
+    Some(lang) if lang.chars().all(|c| c.is_ascii_alphabetic()) && Lang::from_str(lang).is_ok() => {
+      lang.to_lowercase()
+    }
+    _ => {
+      tracing::warn!("Unknown book language `{}`, falling back to `en`", language);
+
+      return (typst_string("en"), "none".to_string());
+    }
+  };
+
+  let region = subtags
+    .find(|subtag| subtag.len() == 2 && subtag.chars().all(|c| c.is_ascii_alphabetic()))
+    .map_or("none".to_string(), |region| {
+      typst_string(&region.to_lowercase())
+    });
+
+  (typst_string(&lang), region)
+}
+
+/// Convert a page header or footer format into typst, the title page has
+/// neither.
+///
+/// `{chapter}` is the last outlined heading up to the current page, which is
+/// the invisible heading of the current chapter.
+fn convert_page_format(format: &str, title: &str) -> String {
+  let tokens = [
+    ("{title}", escape_text(title)),
+    (
+      "{chapter}",
+      "#{ let chapters = query(heading.where(outlined: true)).filter(h => h.location().page() <= here().page()); if chapters.len() > 0 { chapters.last().body } };".to_string(),
+    ),
+    (
+      "{page}",
+      "#counter(page).display(if page.numbering == none { \"1\" } else { page.numbering });"
+        .to_string(),
+    ),
+    ("{total}", "#counter(page).final().first();".to_string()),
+  ];
+
+  let mut content = String::new();
+
+  let mut rest = format;
+
+  while !rest.is_empty() {
+    match tokens.iter().find(|(token, _)| rest.starts_with(token)) {
+      Some((token, value)) => {
+        content.push_str(value);
+
+        rest = &rest[token.len()..];
+      }
+      None => {
+        let ch = rest.chars().next().unwrap_or_default();
+
+        content.push_str(&escape_text(&ch.to_string()));
+
+        rest = &rest[ch.len_utf8()..];
+      }
+    }
+  }
+
+  format!(
+    "context {{\n    if here().page() > 1 [\n      {}\n    ]\n  }}",
+    content
+  )
+}
+
+/// Convert the configured margins into a typst margin dictionary, sides not
+/// configured are left for typst to decide.
+fn convert_margin(cfg: &Config) -> Result<String, anyhow::Error> {
+  let sides = [
+    ("top", &cfg.margin.top),
+    ("bottom", &cfg.margin.bottom),
+    ("left", &cfg.margin.left),
+    ("right", &cfg.margin.right),
+  ];
+
+  let mut margin = Vec::new();
+
+  for (side, value) in sides {
+    if let Some(value) = value {
+      if !length_regex().is_match(value) {
+        return Err(anyhow!("invalid {} margin `{}`", side, value));
+      }
+
+      margin.push(format!("{}: {}", side, value));
+    }
+  }
+
+  if margin.is_empty() {
+    Ok("auto".to_string())
+  } else {
+    Ok(format!("({})", margin.join(", ")))
+  }
+}
+
+/// Table arguments from the `table` section, one indented line each.
+fn convert_table_style(cfg: &Config) -> Result<String, anyhow::Error> {
+  let inset = cfg.table.inset.as_deref().unwrap_or("10pt");
+
+  if !length_regex().is_match(inset) {
+    return Err(anyhow!("invalid table inset `{}`", inset));
+  }
+
+  let mut style = format!("  inset: {},\n", inset);
+
+  if let Some(stroke) = &cfg.table.stroke {
+    let stroke = convert_stroke(stroke).ok_or(anyhow!("invalid table stroke `{}`", stroke))?;
+
+    writeln!(style, "  stroke: {},", stroke)?;
+  }
+
+  if let Some(fill) = &cfg.table.header_fill {
+    let fill = convert_color(fill).ok_or(anyhow!("invalid table header-fill `{}`", fill))?;
+
+    writeln!(style, "  fill: (_, y) => if y == 0 {{ {} }},", fill)?;
+  }
+
+  Ok(style)
+}
+
+/// The `show link` rule of `link-style`, links to urls and links within the
+/// book are styled separately when their options differ.
+fn convert_link_style(cfg: &Config) -> Result<String, anyhow::Error> {
+  let style = |color: Option<&String>, underline: bool| -> Result<String, anyhow::Error> {
+    let link = match color {
+      Some(color) => {
+        let fill = convert_color(color).ok_or(anyhow!("invalid link color `{}`", color))?;
+
+        format!("text(fill: {}, it)", fill)
+      }
+      None => "it".to_string(),
+    };
+
+    Ok(if underline {
+      format!("underline({})", link)
+    } else {
+      link
+    })
+  };
+
+  let link_style = &cfg.link_style;
+
+  let url = style(link_style.color.as_ref(), link_style.underline)?;
+
+  let internal = style(
+    link_style
+      .internal_color
+      .as_ref()
+      .or(link_style.color.as_ref()),
+    link_style
+      .internal_underline
+      .unwrap_or(link_style.underline),
+  )?;
+
+  Ok(if url != internal {
+    format!(
+      "#show link: it => if type(it.dest) == str {{ {} }} else {{ {} }}",
+      url, internal
+    )
+  } else if url != "it" {
+    format!("#show link: it => {}", url)
+  } else {
+    String::new()
+  })
+}
+
+/// The `columns` argument of a table with the given number of columns.
+fn table_columns(cfg: &Config, columns: usize) -> String {
+  match cfg.table.columns {
+    TableColumns::Auto => columns.to_string(),
+    TableColumns::Equal => format!("({})", vec!["1fr"; columns].join(", ")),
+  }
+}
+
+/// Convert a stroke like `0.5pt + gray` into typst, each part may be a
+/// length or a color.
+fn convert_stroke(stroke: &str) -> Option<String> {
+  if stroke.trim() == "none" {
+    return Some("none".to_string());
+  }
+
+  stroke
+    .split('+')
+    .map(|part| {
+      let part = part.trim();
+
+      if length_regex().is_match(part) {
+        Some(part.to_string())
+      } else {
+        convert_color(part)
+      }
+    })
+    .collect::<Option<Vec<String>>>()
+    .map(|parts| parts.join(" + "))
+}
+
+/// Convert a hex color or a typst color name into typst.
+fn convert_color(color: &str) -> Option<String> {
+  const NAMED_COLORS: &[&str] = &[
+    "black", "gray", "silver", "white", "navy", "blue", "aqua", "teal", "eastern", "purple",
+    "fuchsia", "maroon", "red", "orange", "yellow", "olive", "green", "lime",
+  ];
+
+  let color = color.trim();
+
+  if NAMED_COLORS.contains(&color) {
+    return Some(color.to_string());
+  }
+
+  let hex = color.strip_prefix('#')?;
+
+  if matches!(hex.len(), 3 | 4 | 6 | 8) && hex.chars().all(|c| c.is_ascii_hexdigit()) {
+    Some(format!("rgb(\"{}\")", color))
+  } else {
+    None
+  }
+}
+
+fn convert_book_item(
+  ctx: &RenderContext,
+  cfg: &Config,
+  item: &BookItem,
+  figure_labels: &HashSet<String>,
+) -> Result<MappedTypst, anyhow::Error> {
+  let mut book_item_str = String::new();
+
+  let mut chapters = Vec::new();
+
+  if let BookItem::Chapter(ref ch) = *item {
+    // Draft chapters have no content.
+    let Some(label_path) = ch.source_path.to_owned() else {
+      match cfg.draft_chapters {
+        DraftChapters::Skip => tracing::warn!("Skipping draft chapter `{}`", ch.name),
+        DraftChapters::Placeholder => {
+          let level = ch.number.as_ref().map_or(1, |number| number.len());
+
+          let name = match section_number(ctx, cfg, ch) {
+            Some(number) if cfg.section_number => format!("{} {}", number, ch.name),
+            _ => ch.name.clone(),
+          };
+
+          writeln!(
+            book_item_str,
+            "#heading(numbering: none, level: {}, outlined: true)[#{}]\n\n#emph[This chapter is a draft.]\n\n#pagebreak(weak: true)",
+            level,
+            typst_string(&name)
+          )?;
         }
       }
-      Event::SoftBreak => writeln!(content_str)?,
-      _ => (),
+
+      return Ok(book_item_str.into());
+    };
+
+    let label = chapter_label(&label_path).ok_or_else(|| {
+      anyhow!(
+        "label not found for chapter `{}` ({})",
+        ch.name,
+        label_path.display()
+      )
+    })?;
+
+    let chapter_dir = label_path.parent().unwrap_or(Path::new(""));
+
+    let front_matter = front_matter(cfg, &ch.content);
+
+    let (preamble_block, markdown) = split_preamble(cfg, strip_front_matter(cfg, &ch.content));
+
+    let markdown = inline_footnotes(cfg, markdown);
+
+    // The front matter preamble comes before the one of a leading block.
+    let preamble = [front_matter.get("typst-preamble"), preamble_block.as_ref()]
+      .into_iter()
+      .flatten()
+      .map(|preamble| preamble.trim_end())
+      .collect::<Vec<&str>>()
+      .join("\n");
+
+    // The front matter title replaces the name from `SUMMARY.md`.
+    let name = front_matter.get("title").unwrap_or(&ch.name);
+
+    let mut invisible_heading = if let Some(number) = &ch.number {
+      if cfg.section_number {
+        format!(
+          "#{{\n  show heading: none\n  set text(size: 0pt, fill: white)\n  heading(numbering: none, level: {}, outlined: true)[#{}]\n}} <{}.html>",
+          number.len(),
+          typst_string(&format!(
+            "{} {}",
+            section_number(ctx, cfg, ch).unwrap_or_default(),
+            name
+          )),
+          label,
+        )
+      } else {
+        format!(
+          "#{{\n  show heading: none\n  set text(size: 0pt, fill: white)\n  heading(numbering: none, level: {}, outlined: true)[{}]\n}} <{}.html>",
+          number.len(),
+          escape_text(name),
+          label
+        )
+      }
+    } else {
+      format!(
+        "#{{\n  show heading: none\n  set text(size: 0pt, fill: white)\n  heading(numbering: none, level: 1, outlined: true)[{}]\n}} <{}.html>",
+        escape_text(name),
+        label,
+      )
+    };
+
+    if let Some(author) = front_matter.get("author") {
+      write!(invisible_heading, "\n#emph[{}]\n", escape_text(author))?;
+    }
+
+    // Without a page break the heading of an empty chapter, like a parent of
+    // the next chapters, shares the page of the next chapter.
+    if cfg.empty_chapters == EmptyChapters::Merge && is_empty_content(&markdown) {
+      writeln!(book_item_str, "{}", invisible_heading)?;
+
+      return Ok(book_item_str.into());
+    }
+
+    // Pairs of typst and Markdown offsets of the converted events.
+    let mut positions = Vec::new();
+
+    let content = convert_content(
+      ctx,
+      cfg,
+      &markdown,
+      &label,
+      chapter_dir,
+      &invisible_heading,
+      figure_labels,
+      &mut positions,
+    )
+    .with_context(|| {
+      let line = positions
+        .last()
+        .and_then(|(_, offset)| markdown.get(..*offset))
+        .map_or(1, |before| before.matches('\n').count() + 1);
+
+      format!(
+        "failed to convert chapter `{}` ({}) near line {}",
+        ch.name,
+        label_path.display(),
+        line
+      )
+    })?;
+
+    let line_starts: Vec<usize> = std::iter::once(0)
+      .chain(markdown.match_indices('\n').map(|(index, _)| index + 1))
+      .collect();
+
+    let mut lines: Vec<(usize, usize)> = positions
+      .into_iter()
+      .map(|(typst, markdown)| {
+        (
+          typst,
+          line_starts.partition_point(|start| *start <= markdown),
+        )
+      })
+      .collect();
+
+    // Buffered content like headings and footnotes is written out of order.
+    lines.sort_by_key(|(typst, _)| *typst);
+
+    // A preamble is scoped to the chapter by a content block, which ends
+    // before the page break so the break is not inside the block.
+    let content = if preamble.is_empty() {
+      content
+    } else {
+      book_item_str.push_str("#[\n");
+      book_item_str.push_str(&preamble);
+      book_item_str.push('\n');
+
+      format!("{}\n]\n", content.trim_end())
+    };
+
+    chapters.push(MappedChapter {
+      name: ch.name.clone(),
+      path: ctx.config.book.src.join(&label_path),
+      range: book_item_str.len()..book_item_str.len() + content.len(),
+      lines,
+    });
+
+    if !cfg.chapter_pagebreak {
+      writeln!(book_item_str, "{}#v(2em, weak: true)", content)?;
+    } else {
+      writeln!(book_item_str, "{}#pagebreak(weak: true)", content)?;
+    }
+  } else if let BookItem::PartTitle(ref title) = *item {
+    writeln!(
+      book_item_str,
+      "#pagebreak(weak: true)\n#align(center + horizon)[\n  #heading(numbering: none, level: 1, outlined: true)[#{}]\n]\n#pagebreak(weak: true)",
+      typst_string(title)
+    )?;
+  } else if let BookItem::Separator = *item {
+    // A rule after a page break would be alone on its page.
+    if !cfg.chapter_pagebreak {
+      writeln!(book_item_str, "#line(length: 100%)")?;
     }
   }
 
-  Ok(content_str)
+  Ok(MappedTypst {
+    typst: book_item_str,
+    chapters,
+  })
+}
+
+/// Convert the Markdown of a chapter, `positions` receives the typst and
+/// Markdown byte offsets of each event, the last one locates errors.
+#[allow(clippy::too_many_arguments)]
+fn convert_content(
+  ctx: &RenderContext,
+  cfg: &Config,
+  content: &str,
+  label: &str,
+  chapter_dir: &Path,
+  invisible_heading: &str,
+  figure_labels: &HashSet<String>,
+  positions: &mut Vec<(usize, usize)>,
+) -> Result<String, anyhow::Error> {
+  let mut content_str = String::new();
+
+  let mut heading = String::new();
+
+  // An explicit `{#id}` of the current heading.
+  let mut heading_id: Option<String> = None;
+
+  let mut writen_invisible_heading = false;
+
+  let mut footnotes: HashMap<String, String> = HashMap::new();
+
+  let mut footnote_refs: Vec<String> = Vec::new();
+
+  let mut footnote_outer = String::new();
+
+  let mut footnote_name: Option<String> = None;
+
+  // The positions of footnote definitions, relative to their trimmed body and
+  // moved to the footnote once the body is written in place of a reference.
+  let mut footnote_positions: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+
+  let mut footnote_positions_start = 0;
+
+  let mut item_marker = 0;
+
+  let mut linebreak_end = 0;
+
+  let mut html_table: Option<String> = None;
+
+  // Open HTML `<div>` elements, whether they started a styled box.
+  let mut html_divs: Vec<bool> = Vec::new();
+
+  // Open HTML `<span>` elements, the brackets closing their styles.
+  let mut html_spans: Vec<String> = Vec::new();
+
+  // The source of a code block rendered by a configured command.
+  let mut code_block_source = String::new();
+
+  let mut definition_term_start = 0;
+
+  let mut definition_terms: Vec<String> = Vec::new();
+
+  // The current image's path in the destination, url and title. Its alt text
+  // is converted like body text from `image_start` on and becomes the caption.
+  let mut image: Option<(Option<String>, String, String)> = None;
+
+  let mut image_start = 0;
+
+  let mut image_alt = String::new();
+
+  // Figure ids already used in this chapter.
+  let mut figure_ids: HashSet<String> = HashSet::new();
+
+  // A link to a figure, its text from the position on becomes the supplement.
+  let mut figure_ref: Option<(usize, String)> = None;
+
+  let table_style = convert_table_style(cfg)?;
+
+  // Unresolved reference links are kept as links with an empty destination
+  // and rendered as their plain text. Shortcut references like `array[0]` are
+  // mostly literal brackets, so they neither warn nor lose their brackets.
+  let mut broken_link_callback = |link: BrokenLink| {
+    if link.link_type != LinkType::Shortcut {
+      tracing::warn!(
+        "Unresolved link reference `{}` in `{}`",
+        link.reference,
+        label
+      );
+    }
+
+    Some((CowStr::Borrowed(""), CowStr::Borrowed("")))
+  };
+
+  let parser =
+    Parser::new_with_broken_link_callback(content, options(cfg), Some(&mut broken_link_callback));
+
+  // Links rendered as their plain text.
+  let mut plain_link: Option<LinkType> = None;
+
+  let mut in_link = false;
+
+  // A citation like `[@key]`, its link text from the position on is dropped.
+  let mut citation_ref: Option<(usize, String)> = None;
+
+  let mut event_stack = Vec::new();
+
+  for (event, range) in TextMergeWithOffset::new(parser.into_offset_iter()) {
+    positions.push((content_str.len(), range.start));
+
+    match event {
+      Event::Start(Tag::Heading { level, id, .. }) => {
+        event_stack.push(EventType::Heading);
+
+        heading.clear();
+
+        heading_id = id.map(|id| id.to_string());
+
+        let level_usize: usize = level as usize;
+
+        write!(
+          content_str,
+          "#heading(level: {}, outlined: false)[",
+          level_usize,
+        )?;
+      }
+      Event::End(TagEnd::Heading(_)) => {
+        event_stack.pop();
+
+        writeln!(
+          content_str,
+          "] <{}.html-{}>",
+          label,
+          heading_id
+            .take()
+            .unwrap_or_else(|| mdbook::utils::normalize_id(&heading))
+        )?;
+
+        if !writen_invisible_heading {
+          writeln!(content_str, "{}", invisible_heading)?;
+
+          writen_invisible_heading = true;
+        }
+      }
+      Event::Start(Tag::Emphasis) => write!(content_str, "#emph[")?,
+      Event::End(TagEnd::Emphasis) => write!(content_str, "]")?,
+      Event::Start(Tag::Strong) => write!(content_str, "#strong[")?,
+      Event::End(TagEnd::Strong) => write!(content_str, "]")?,
+      Event::FootnoteReference(name) => {
+        write!(content_str, "{}", footnote_marker(&name))?;
+
+        footnote_refs.push(name.to_string());
+      }
+      Event::Start(Tag::FootnoteDefinition(name)) => {
+        footnote_name = Some(name.to_string());
+
+        footnote_outer = std::mem::take(&mut content_str);
+
+        footnote_positions_start = positions.len();
+      }
+      Event::End(TagEnd::FootnoteDefinition) => {
+        // The definition body was written into a fresh buffer, restore the
+        // chapter content and keep the body for the references.
+        let body = std::mem::replace(&mut content_str, std::mem::take(&mut footnote_outer));
+
+        let leading = body.len() - body.trim_start().len();
+        let body = body.trim();
+
+        let body_positions = positions
+          .drain(footnote_positions_start..)
+          .map(|(typst, markdown)| (typst.saturating_sub(leading).min(body.len()), markdown))
+          .collect();
+
+        if let Some(name) = footnote_name.take() {
+          footnote_positions.insert(name.clone(), body_positions);
+          footnotes.insert(name, body.to_string());
+        }
+      }
+      Event::Start(Tag::DefinitionList) => {
+        if !content_str.is_empty() && !content_str.ends_with('\n') {
+          writeln!(content_str)?;
+        }
+
+        writeln!(content_str, "#terms(")?
+      }
+      Event::End(TagEnd::DefinitionList) => write!(content_str, ")\n\n")?,
+      Event::Start(Tag::DefinitionListTitle) => definition_term_start = content_str.len(),
+      Event::End(TagEnd::DefinitionListTitle) => {
+        let term = content_str.split_off(definition_term_start);
+
+        definition_terms.push(term.trim().to_string());
+      }
+      Event::Start(Tag::DefinitionListDefinition) => {
+        // Several terms share the definition following them.
+        write!(
+          content_str,
+          "  terms.item[{}][",
+          std::mem::take(&mut definition_terms).join(" \\ ")
+        )?
+      }
+      Event::End(TagEnd::DefinitionListDefinition) => {
+        content_str.truncate(content_str.trim_end().len());
+
+        writeln!(content_str, "],")?
+      }
+      Event::Start(Tag::Strikethrough) => write!(content_str, "#strike[")?,
+      Event::End(TagEnd::Strikethrough) => write!(content_str, "]")?,
+      Event::Start(Tag::BlockQuote(kind)) => {
+        if content_str.ends_with('\n') {
+          write!(content_str, "{}", "  ".repeat(list_depth(&event_stack)))?;
+        }
+
+        // GitHub alerts like `> [!NOTE]` start with their kind.
+        match kind {
+          Some(kind) => {
+            let (title, icon, color) = alert_style(kind);
+
+            if cfg.alerts {
+              write!(
+                content_str,
+                "{}[#text(fill: rgb(\"{color}\"))[*{icon} {title}*]\n\n",
+                alert_block(color)
+              )?
+            } else {
+              write!(content_str, "#quote(block: true)[*{}*\n\n", title)?
+            }
+          }
+          None => write!(content_str, "#quote(block: true)[")?,
+        }
+      }
+      Event::End(TagEnd::BlockQuote(_)) => {
+        if content_str.ends_with('\n') {
+          write!(content_str, "{}", "  ".repeat(list_depth(&event_stack)))?;
+        }
+
+        writeln!(content_str, "]")?
+      }
+      Event::Start(Tag::List(start)) => {
+        // A nested list starts right after the text of its parent item.
+        if !content_str.is_empty() && !content_str.ends_with('\n') {
+          writeln!(content_str)?;
+        }
+
+        match start {
+          None => event_stack.push(EventType::List),
+          Some(start) => event_stack.push(EventType::NumberedList(start)),
+        }
+      }
+      Event::End(TagEnd::List(_)) => {
+        event_stack.pop();
+
+        if list_depth(&event_stack) == 0 {
+          writeln!(content_str)?;
+        }
+      }
+      Event::Start(Tag::Item) => {
+        write!(
+          content_str,
+          "{}",
+          "  ".repeat(list_depth(&event_stack).saturating_sub(1))
+        )?;
+
+        item_marker = content_str.len();
+
+        match event_stack.last_mut() {
+          Some(EventType::List) => write!(content_str, "- ")?,
+          Some(EventType::NumberedList(number)) => {
+            // Explicit numbers keep the start of the list and resumed lists
+            // intact, Typst's `+ ` would always count from 1.
+            write!(content_str, "{}. ", number)?;
+
+            *number += 1;
+          }
+          _ => write!(content_str, "- ")?,
+        }
+      }
+      Event::End(TagEnd::Item) => {
+        if event_stack.last() == Some(&EventType::TaskListItem) {
+          event_stack.pop();
+
+          write!(content_str, "]")?;
+        }
+
+        // Items of loose lists end with the blank line of their last paragraph,
+        // which makes the typst list wide. Items of tight lists, also those
+        // ending with a nested list, must not be separated by a blank line.
+        if !content_str.ends_with('\n') {
+          writeln!(content_str)?
+        }
+      }
+      Event::TaskListMarker(checked) => {
+        content_str.truncate(item_marker);
+
+        event_stack.push(EventType::TaskListItem);
+
+        write!(
+          content_str,
+          "#list(marker: [{}])[",
+          if checked { "☒" } else { "☐" }
+        )?
+      }
+      Event::Start(Tag::Paragraph) if content_str.ends_with('\n') => {
+        write!(content_str, "{}", "  ".repeat(list_depth(&event_stack)))?
+      }
+      Event::Start(Tag::Paragraph) => (),
+      Event::End(TagEnd::Paragraph) => {
+        // A line break right before the paragraph break would add an empty line.
+        if content_str.len() == linebreak_end {
+          content_str.truncate(linebreak_end - 2);
+        }
+
+        write!(content_str, "\n\n")?
+      }
+      Event::Start(Tag::Link {
+        link_type:
+          link_type @ (LinkType::ReferenceUnknown
+          | LinkType::CollapsedUnknown
+          | LinkType::ShortcutUnknown),
+        ..
+      }) => {
+        let citation = match link_type {
+          LinkType::ShortcutUnknown if cfg.bibliography.is_some() => {
+            content.get(range.clone()).and_then(convert_citation)
+          }
+          _ => None,
+        };
+
+        match citation {
+          Some(citation) => citation_ref = Some((content_str.len(), citation)),
+          None if link_type == LinkType::ShortcutUnknown => write!(content_str, "\\[")?,
+          None => (),
+        }
+
+        plain_link = Some(link_type);
+
+        in_link = true;
+      }
+      Event::End(TagEnd::Link) if plain_link.is_some() => {
+        if let Some((start, citation)) = citation_ref.take() {
+          content_str.truncate(start);
+
+          write!(content_str, "{}", citation)?;
+        } else if plain_link == Some(LinkType::ShortcutUnknown) {
+          write!(content_str, "\\]")?;
+        }
+
+        plain_link = None;
+
+        in_link = false;
+      }
+      Event::Start(Tag::Link {
+        link_type,
+        dest_url,
+        ..
+      }) => {
+        in_link = true;
+
+        if dest_url.starts_with("http://")
+          || dest_url.starts_with("https://")
+          || dest_url.starts_with("mailto:")
+        {
+          write!(content_str, "#link({})[", typst_string(&dest_url))?
+        } else if link_type == LinkType::Email || email_regex().is_match(&dest_url) {
+          write!(
+            content_str,
+            "#link({})[",
+            typst_string(&format!("mailto:{}", dest_url))
+          )?
+        } else if let Some(link_label) = link_label(label, chapter_dir, &dest_url) {
+          if figure_labels.contains(&link_label) {
+            figure_ref = Some((content_str.len(), link_label));
+          } else {
+            write!(content_str, "#link(<{}>)[", link_label)?
+          }
+        } else {
+          let file_path =
+            resolve_src_path(chapter_dir, dest_url.split('#').next().unwrap_or_default());
+
+          if src_dir(ctx)?.join(&file_path).is_file() {
+            copy_src_file(ctx, &file_path)?;
+
+            write!(
+              content_str,
+              "#link({})[",
+              typst_string(&format!("file:{}", file_path))
+            )?
+          } else {
+            tracing::warn!("Link target `{}` not found in `{}`", dest_url, label);
+
+            plain_link = Some(link_type);
+          }
+        }
+      }
+      Event::End(TagEnd::Link) => {
+        in_link = false;
+
+        match figure_ref.take() {
+          Some((start, link_label)) => {
+            let supplement = content_str.split_off(start);
+
+            if supplement.trim().is_empty() {
+              write!(content_str, "#ref(<{}>)", link_label)?
+            } else {
+              write!(
+                content_str,
+                "#ref(<{}>, supplement: [{}])",
+                link_label, supplement
+              )?
+            }
+          }
+          None => write!(content_str, "]")?,
+        }
+      }
+      Event::Start(Tag::Table(align)) => {
+        let typst_align = align
+          .iter()
+          .map(|a| match a {
+            Alignment::None => "auto",
+            Alignment::Left => "left",
+            Alignment::Center => "center",
+            Alignment::Right => "right",
+          })
+          .collect::<Vec<&str>>()
+          .join(", ");
+
+        writeln!(
+          content_str,
+          "#table(\n  columns: {},\n{}  align: ({}),\n  ",
+          table_columns(cfg, align.len()),
+          table_style,
+          typst_align
+        )?
+      }
+      Event::End(TagEnd::Table) => writeln!(content_str, ")")?,
+      Event::Start(Tag::TableHead) => {
+        event_stack.push(EventType::TableHead);
+      }
+      Event::End(TagEnd::TableHead) => {
+        event_stack.pop();
+      }
+      Event::Start(Tag::TableRow) => (),
+      Event::End(TagEnd::TableRow) => (),
+      // Header cells are strong as a whole, so their content is escaped and
+      // converted like any other cell.
+      Event::Start(Tag::TableCell) => {
+        if event_stack.last() == Some(&EventType::TableHead) {
+          write!(content_str, "[#strong[")?
+        } else {
+          write!(content_str, "[")?
+        }
+      }
+      Event::End(TagEnd::TableCell) => {
+        if event_stack.last() == Some(&EventType::TableHead) {
+          writeln!(content_str, "]],")?
+        } else {
+          writeln!(content_str, "],")?
+        }
+      }
+      Event::Start(Tag::Image {
+        dest_url, title, ..
+      }) => {
+        event_stack.push(EventType::Image);
+
+        image = Some((
+          image_path(ctx, cfg, chapter_dir, &dest_url)?,
+          dest_url.to_string(),
+          title.to_string(),
+        ));
+
+        image_start = content_str.len();
+
+        image_alt.clear();
+      }
+      Event::End(TagEnd::Image) => {
+        event_stack.pop();
+
+        let alt = content_str.split_off(image_start);
+
+        let alt = figure_id_regex().replace(&alt, "");
+
+        if let Some((image_path, dest_url, title)) = image.take() {
+          let caption = if title.is_empty() {
+            alt.trim().to_string()
+          } else {
+            escape_text(&title)
+          };
+
+          match image_path {
+            Some(image_path) => {
+              write!(
+                content_str,
+                "#figure(\n  image({}),\n  kind: image",
+                typst_string(&image_path)
+              )?;
+
+              if !caption.is_empty() {
+                write!(content_str, ",\n  caption: [{}]", caption)?;
+              }
+
+              write!(content_str, "\n)")?;
+
+              if let Some(id) = figure_id(&image_alt) {
+                if figure_ids.insert(id.clone()) {
+                  write!(content_str, " <{}.html-{}>", label, id)?;
+                } else {
+                  tracing::warn!("Duplicate figure id `{}` in `{}`", id, label);
+                }
+              }
+            }
+            None if caption.is_empty() => {
+              write!(content_str, "#link({})", typst_string(&dest_url))?
+            }
+            None => write!(
+              content_str,
+              "#link({})[{}]",
+              typst_string(&dest_url),
+              caption
+            )?,
+          }
+        }
+
+        writeln!(content_str)?
+      }
+      Event::Start(Tag::CodeBlock(ref lang)) => {
+        // In tight list items the code block follows the item text directly.
+        if !content_str.is_empty() && !content_str.ends_with('\n') && list_depth(&event_stack) > 0 {
+          writeln!(content_str)?;
+        }
+
+        if content_str.ends_with('\n') {
+          write!(content_str, "{}", "  ".repeat(list_depth(&event_stack)))?;
+        }
+
+        match lang {
+          CodeBlockKind::Indented => {
+            event_stack.push(EventType::CodeBlockIndented);
+
+            match code_block_show_rule(cfg, &[]) {
+              Some(show_rule) => writeln!(content_str, "#[\n{}\n````", show_rule)?,
+              None => writeln!(content_str, "````")?,
+            }
+          }
+          CodeBlockKind::Fenced(lang)
+            if cfg.passthrough_lang.as_deref() == Some(code_block_name(lang)) =>
+          {
+            event_stack.push(EventType::CodeBlockPassthrough);
+          }
+          CodeBlockKind::Fenced(lang)
+            if cfg.code_block_render.contains_key(code_block_name(lang)) =>
+          {
+            event_stack.push(EventType::CodeBlockRender(
+              code_block_name(lang).to_string(),
+            ));
+
+            code_block_source.clear();
+          }
+          CodeBlockKind::Fenced(lang) => {
+            event_stack.push(EventType::CodeBlockFenced(lang.to_string()));
+
+            let langs: Vec<&str> = lang.split(',').collect();
+
+            if !langs.is_empty() {
+              let mut ferris_prefix = "".to_string();
+
+              let mut hl_lines = Vec::new();
+
+              for l in langs.iter().skip(1) {
+                match l {
+                  l if ferris_image(l).is_some() => {
+                    ferris_prefix = "#columns(1)[\n".to_string();
+                  }
+                  l if l.starts_with("hl_lines=") => hl_lines = parse_hl_lines(l),
+                  _ => (),
+                }
+              }
+
+              let show_rule = code_block_show_rule(cfg, &hl_lines)
+                .map(|show_rule| format!("#[\n{}\n", show_rule))
+                .unwrap_or_default();
+
+              writeln!(
+                content_str,
+                "{}{}````{}",
+                ferris_prefix,
+                show_rule,
+                code_block_lang(cfg, langs[0])
+              )?
+            } else {
+              writeln!(content_str, "````")?
+            }
+          }
+        }
+      }
+      Event::End(TagEnd::CodeBlock) => {
+        match event_stack.last() {
+          Some(EventType::CodeBlockIndented) => match code_block_show_rule(cfg, &[]) {
+            Some(_) => writeln!(content_str, "````\n]")?,
+            None => writeln!(content_str, "````")?,
+          },
+          // A blank line keeps passed through markup out of the next paragraph.
+          Some(EventType::CodeBlockPassthrough) => {
+            if !content_str.ends_with('\n') {
+              content_str.push('\n');
+            }
+
+            content_str.push('\n');
+          }
+          Some(EventType::CodeBlockRender(lang)) => {
+            let command = &cfg.code_block_render[lang];
+
+            let image_path = render_code_block(ctx, lang, command, &code_block_source)?;
+
+            writeln!(content_str, "#figure(image({}))", typst_string(&image_path))?;
+          }
+          Some(EventType::CodeBlockFenced(lang)) => {
+            let langs: Vec<&str> = lang.split(',').collect();
+
+            if !langs.is_empty() {
+              let mut ferris_suffix = "".to_string();
+
+              let mut hl_lines = Vec::new();
+
+              for l in langs.iter().skip(1) {
+                match (l, ferris_image(l)) {
+                  (l, _) if l.starts_with("hl_lines=") => hl_lines = parse_hl_lines(l),
+                  (_, Some(ferris)) => {
+                    let ferris_src_path = format!("img/ferris/{}.svg", ferris);
+
+                    let src_path = ctx
+                      .root
+                      .join(
+                        ctx
+                          .config
+                          .book
+                          .src
+                          .to_str()
+                          .ok_or(anyhow!("src not found"))?,
+                      )
+                      .join(&ferris_src_path);
+
+                    let dest_path = ctx.destination.join(&ferris_src_path);
+
+                    if !dest_path.exists() {
+                      copy_file(&src_path, &dest_path).map_err(|err| {
+                        anyhow!(
+                          "failed to copy ferris image `{}`: {}",
+                          src_path.display(),
+                          err
+                        )
+                      })?;
+                    }
+
+                    ferris_suffix = format!(
+                      "\n#place(\n  top + right,\n  figure(\n    image(\"{}\", width: 10%)\n  )\n)\n]",
+                      ferris_src_path
+                    );
+                  }
+                  _ => (),
+                }
+              }
+
+              let show_rule_end = if code_block_show_rule(cfg, &hl_lines).is_some() {
+                "\n]"
+              } else {
+                ""
+              };
+
+              writeln!(content_str, "````{}{}", show_rule_end, ferris_suffix)?
+            } else {
+              writeln!(content_str, "````")?
+            }
+          }
+          _ => writeln!(content_str, "````")?,
+        }
+
+        event_stack.pop();
+      }
+      Event::Code(t) => {
+        // Only the code text is part of the heading id, like the HTML id of
+        // `<code>` in mdBook.
+        if event_stack.contains(&EventType::Heading) {
+          heading.push_str(&t);
+        }
+
+        write!(
+          content_str,
+          r#"#raw("{}")"#,
+          t.replace('\\', r#"\\"#).replace('"', r#"\""#)
+        )?;
+      }
+      Event::InlineMath(t) => {
+        if event_stack.contains(&EventType::Heading) {
+          heading.push_str(&t);
+        }
+
+        write!(content_str, "${}$", convert_math(t.trim()))?;
+      }
+      Event::DisplayMath(t) => {
+        if event_stack.contains(&EventType::Heading) {
+          heading.push_str(&t);
+        }
+
+        write!(content_str, "$ {} $", convert_math(t.trim()))?;
+      }
+      Event::Html(t)
+        if html_table.is_some() || t.trim_start().to_lowercase().starts_with("<table") =>
+      {
+        // A HTML table spans multiple HTML events, collect them up to the end
+        // of the table.
+        let table = html_table.get_or_insert_with(String::new);
+
+        table.push_str(&t);
+
+        if table.to_lowercase().contains("</table>") {
+          let dom = parse_document(RcDom::default(), Default::default())
+            .from_utf8()
+            .read_from(&mut table.as_bytes())?;
+
+          if let Some(table) = find_element(&dom.document, "table") {
+            write!(
+              content_str,
+              "{}",
+              convert_html_table(cfg, &table, &table_style)
+            )?;
+          }
+
+          html_table = None;
+        }
+      }
+      Event::Html(t) | Event::InlineHtml(t) => {
+        if let Some(directive) = comment_directive(&t) {
+          match convert_comment_directive(directive) {
+            Some(typst) => writeln!(content_str, "{}", typst)?,
+            None => tracing::warn!("Unknown typst directive `{}` in `{}`", directive, label),
+          }
+
+          continue;
+        }
+
+        match t.trim().to_lowercase().as_str() {
+          "<br>" | "<br/>" | "<br />" => {
+            write!(content_str, "\\ ")?;
+
+            linebreak_end = content_str.len();
+
+            continue;
+          }
+          "<sup>" => {
+            write!(content_str, "#super[")?;
+
+            continue;
+          }
+          "</sup>" | "</sub>" => {
+            write!(content_str, "]")?;
+
+            continue;
+          }
+          "<sub>" => {
+            write!(content_str, "#sub[")?;
+
+            continue;
+          }
+          "</span>" => {
+            if let Some(close) = html_spans.pop() {
+              write!(content_str, "{}", close)?;
+            }
+
+            continue;
+          }
+          "</div>" => {
+            if html_divs.pop() == Some(true) {
+              writeln!(content_str, "]")?;
+            }
+
+            continue;
+          }
+          _ => (),
+        }
+
+        let dom = parse_document(RcDom::default(), Default::default())
+          .from_utf8()
+          .read_from(&mut t.as_bytes())?;
+
+        let dom_children = &dom.document.children.borrow();
+
+        if !dom_children.is_empty() && matches!(dom_children[0].data, NodeData::Element { .. }) {
+          let html_children = &dom_children[0].children.borrow();
+
+          if html_children.len() > 1 {
+            let body_children = &html_children[1].children.borrow();
+
+            if !body_children.is_empty() {
+              if let NodeData::Element { name, attrs, .. } = &body_children[0].data {
+                match name.local.as_ref() {
+                  "img" => {
+                    let attrs = attrs.borrow();
+
+                    let attr = |name: &str| {
+                      attrs
+                        .iter()
+                        .find(|a| a.name.local.as_ref() == name)
+                        .map(|a| a.value.to_string())
+                    };
+
+                    if let Some(attr_src_path) = attr("src") {
+                      let Some(image_path) = image_path(ctx, cfg, chapter_dir, &attr_src_path)?
+                      else {
+                        writeln!(content_str, "#link({})", typst_string(&attr_src_path))?;
+                        continue;
+                      };
+
+                      let style = attr("style").unwrap_or_default();
+
+                      let mut image_args = String::new();
+
+                      for dimension in ["width", "height"] {
+                        let length = attr(dimension)
+                          .or_else(|| css_property(&style, dimension))
+                          .and_then(|l| html_length(&l));
+
+                        if let Some(length) = length {
+                          write!(image_args, ", {}: {}", dimension, length)?;
+                        }
+                      }
+
+                      writeln!(
+                        content_str,
+                        "#figure(\n  image({}{})\n)",
+                        typst_string(&image_path),
+                        image_args
+                      )?
+                    }
+                  }
+                  // Boxes like `<div class="warning">` of the Rust book, their
+                  // Markdown content follows as separate events up to `</div>`.
+                  "div" => {
+                    let attrs = attrs.borrow();
+
+                    let kind = attrs
+                      .iter()
+                      .find(|a| a.name.local.as_ref() == "class")
+                      .and_then(|a| a.value.split_whitespace().find_map(div_kind));
+
+                    let Some(kind) = kind else {
+                      if !t.to_lowercase().contains("</div>") {
+                        html_divs.push(false);
+                      }
+
+                      continue;
+                    };
+
+                    if cfg.alerts {
+                      write!(content_str, "{}[", alert_block(alert_style(kind).2))?;
+                    } else {
+                      write!(content_str, "#quote(block: true)[")?;
+                    }
+
+                    if t.to_lowercase().contains("</div>") {
+                      writeln!(
+                        content_str,
+                        "{}]",
+                        escape_text(&node_text(&body_children[0]))
+                      )?;
+                    } else {
+                      writeln!(content_str)?;
+
+                      html_divs.push(true);
+                    }
+                  }
+                  // The content of a `<span>` follows as separate events up to
+                  // `</span>`, unless the span is complete.
+                  "span" => {
+                    let attrs = attrs.borrow();
+
+                    let style = attrs
+                      .iter()
+                      .find(|a| a.name.local.as_ref() == "style")
+                      .map(|a| a.value.to_string())
+                      .unwrap_or_default();
+
+                    let css_color = |names: &[&str]| {
+                      names
+                        .iter()
+                        .find_map(|name| css_property(&style, name))
+                        .and_then(|color| convert_color(&color.to_lowercase()))
+                    };
+
+                    let mut open = String::new();
+
+                    let mut close = String::new();
+
+                    if let Some(fill) = css_color(&["background-color", "background"]) {
+                      write!(open, "#highlight(fill: {})[", fill)?;
+                      close.push(']');
+                    }
+
+                    if let Some(fill) = css_color(&["color"]) {
+                      write!(open, "#text(fill: {})[", fill)?;
+                      close.push(']');
+                    }
+
+                    if t.to_lowercase().contains("</span>") {
+                      write!(
+                        content_str,
+                        "{}{}{}",
+                        open,
+                        escape_text(&node_text(&body_children[0])),
+                        close
+                      )?;
+                    } else {
+                      write!(content_str, "{}", open)?;
+
+                      html_spans.push(close);
+                    }
+                  }
+                  _ => (),
+                }
+              }
+            }
+          }
+        }
+      }
+      Event::Text(t) => {
+        if event_stack.contains(&EventType::Heading) {
+          heading.push_str(&t);
+        }
+
+        if event_stack.contains(&EventType::Image) {
+          image_alt.push_str(&t);
+        }
+
+        match event_stack.last() {
+          Some(EventType::CodeBlockIndented) => write!(content_str, "{}", t)?,
+          Some(EventType::CodeBlockFenced(_)) => write!(content_str, "{}", t)?,
+          Some(EventType::CodeBlockRender(_)) => code_block_source.push_str(&t),
+          Some(EventType::CodeBlockPassthrough) => write!(content_str, "{}", t)?,
+          _ => {
+            let text = strip_directives(&t, label);
+
+            let mut rest = text.as_ref();
+
+            if cfg.bare_urls && !in_link {
+              while let Some((start, url)) = find_bare_url(rest) {
+                write_text(cfg, &mut content_str, &rest[..start])?;
+
+                write!(content_str, "#link({})", typst_string(url))?;
+
+                rest = &rest[start + url.len()..];
+              }
+            }
+
+            write_text(cfg, &mut content_str, rest)?
+          }
+        }
+      }
+      Event::Rule => {
+        // In list items the rule is indented like the item's paragraphs, an
+        // unindented line would end the list.
+        if content_str.ends_with('\n') {
+          write!(content_str, "{}", "  ".repeat(list_depth(&event_stack)))?;
+        }
+
+        writeln!(content_str, "#line(length: 100%)\n")?
+      }
+      Event::SoftBreak => write!(content_str, "\n{}", "  ".repeat(list_depth(&event_stack)))?,
+      Event::HardBreak => write!(content_str, "\\\n{}", "  ".repeat(list_depth(&event_stack)))?,
+      _ => (),
+    }
+  }
+
+  // Footnote definitions may appear before or after their references, so
+  // references are resolved only once the whole chapter has been converted.
+  let mut labeled_footnotes = HashSet::new();
+
+  for name in footnote_refs.iter() {
+    let footnote_label = format!("{}.html-fn-{}", label, mdbook::utils::normalize_id(name));
+
+    // The definition body is written with the first reference.
+    let mut body_positions = None;
+
+    let footnote = match footnotes.get(name) {
+      Some(_) if labeled_footnotes.contains(name) => format!("#footnote(<{}>)", footnote_label),
+      Some(body) => {
+        labeled_footnotes.insert(name);
+
+        body_positions = footnote_positions.remove(name);
+
+        format!("#footnote[{}] <{}>", body, footnote_label)
+      }
+      None => format!("#super[{}]", escape_text(name)),
+    };
+
+    let marker = footnote_marker(name);
+
+    if let Some(start) = content_str.find(&marker) {
+      content_str.replace_range(start..start + marker.len(), &footnote);
+
+      for (typst, _) in positions.iter_mut() {
+        if *typst >= start + marker.len() {
+          *typst = *typst + footnote.len() - marker.len();
+        }
+      }
+
+      let body_start = start + "#footnote[".len();
+
+      positions.extend(
+        body_positions
+          .into_iter()
+          .flatten()
+          .map(|(typst, markdown)| (body_start + typst, markdown)),
+      );
+    }
+  }
+
+  // Chapters without a heading still get their label and outline entry.
+  if !writen_invisible_heading && !invisible_heading.is_empty() {
+    let heading = format!("{}\n", invisible_heading);
+
+    content_str.insert_str(0, &heading);
+
+    for (typst, _) in positions.iter_mut() {
+      *typst += heading.len();
+    }
+  }
+
+  Ok(content_str)
+}
+
+/// Map a code block language to a language typst can highlight, unknown
+/// languages are emitted without a language.
+fn code_block_lang(cfg: &Config, lang: &str) -> String {
+  let lang = cfg
+    .lang_aliases
+    .get(lang)
+    .map(String::as_str)
+    .or_else(|| {
+      LANG_ALIASES
+        .iter()
+        .find(|(alias, _)| *alias == lang)
+        .map(|(_, l)| *l)
+    })
+    .unwrap_or(lang);
+
+  let raw_langs = RAW_LANGS.get_or_init(|| {
+    RawElem::languages()
+      .into_iter()
+      .flat_map(|(name, extensions)| {
+        std::iter::once(name.to_lowercase()).chain(extensions.into_iter().map(str::to_lowercase))
+      })
+      .collect()
+  });
+
+  if raw_langs.contains(&lang.to_lowercase()) {
+    lang.to_string()
+  } else {
+    String::new()
+  }
+}
+
+/// The Ferris image shown next to a code block with the given annotation.
+///
+/// Annotations without an image like `ignore`, `no_run` or `editable` only
+/// matter to mdBook and are dropped.
+fn ferris_image(annotation: &str) -> Option<&'static str> {
+  match annotation {
+    "does_not_compile" | "compile_fail" => Some("does_not_compile"),
+    "panics" | "should_panic" => Some("panics"),
+    "not_desired_behavior" => Some("not_desired_behavior"),
+    _ => None,
+  }
+}
+
+/// Parse the lines of a `hl_lines=2-4` code block annotation, lines are
+/// separated by spaces and may be ranges. Ranges are kept as they are, a
+/// range past the end of the block highlights up to its last line.
+fn parse_hl_lines(annotation: &str) -> Vec<RangeInclusive<usize>> {
+  annotation
+    .trim_start_matches("hl_lines=")
+    .trim_matches('"')
+    .split_whitespace()
+    .filter_map(|part| {
+      let (start, end) = part.split_once('-').unwrap_or((part, part));
+
+      match (start.parse::<usize>(), end.parse::<usize>()) {
+        (Ok(start), Ok(end)) if start <= end => Some(start..=end),
+        _ => {
+          tracing::warn!("Invalid hl_lines `{}`", part);
+
+          None
+        }
+      }
+    })
+    .collect()
+}
+
+/// A `raw.line` show rule numbering, highlighting and wrapping code block
+/// lines, none when neither is needed.
+fn code_block_show_rule(cfg: &Config, hl_lines: &[RangeInclusive<usize>]) -> Option<String> {
+  if !cfg.code_line_numbers && hl_lines.is_empty() && !cfg.code_wrap {
+    return None;
+  }
+
+  // Wrapped lines are laid out in a box filling the rest of the row, which
+  // holds the line's content instead of the line itself.
+  let mut body = String::from(if cfg.code_wrap { "it.body" } else { "it" });
+
+  if !hl_lines.is_empty() {
+    // Range checks instead of the listed lines, `hl_lines=1-100000` is one
+    // comparison.
+    let lines = hl_lines
+      .iter()
+      .map(|lines| {
+        if lines.start() == lines.end() {
+          format!("it.number == {}", lines.start())
+        } else {
+          format!(
+            "(it.number >= {} and it.number <= {})",
+            lines.start(),
+            lines.end()
+          )
+        }
+      })
+      .collect::<Vec<String>>()
+      .join(" or ");
+
+    body = format!(
+      "if {} {{ highlight(fill: rgb(\"#fff5b1\"), {}) }} else {{ {} }}",
+      lines, body, body
+    );
+  }
+
+  if cfg.code_wrap {
+    // Long words may break anywhere. Continued rows are indented and marked
+    // with `↪`, their number follows from the height of the laid out line.
+    body = format!(
+      concat!(
+        "layout(size => context {{ ",
+        "let row = measure(block(width: size.width, [0])).height + par.leading.to-absolute(); ",
+        "let body = {{ set par(hanging-indent: 1.5em); ",
+        "show regex(\"\\S{{20,}}\"): t => t.text.clusters().join(\"\\u{{200b}}\"); {} }}; ",
+        "let rows = int(calc.round((measure(block(width: size.width, body)).height + par.leading.to-absolute()) / row)); ",
+        "body; ",
+        "for k in range(1, rows) {{ place(top + left, dy: k * row, text(fill: gray)[↪]) }} ",
+        "}})"
+      ),
+      body
+    );
+  }
+
+  body = match (cfg.code_line_numbers, cfg.code_wrap) {
+    (true, true) => format!(
+      "box(width: 1fr, grid(columns: (2em, 1fr), column-gutter: 1em, align(right, text(fill: gray)[#it.number]), {}))",
+      body
+    ),
+    (true, false) => format!(
+      "box(width: 2em, align(right, text(fill: gray)[#it.number])) + h(1em) + {{ {} }}",
+      body
+    ),
+    (false, true) => format!("box(width: 1fr, {})", body),
+    (false, false) => body,
+  };
+
+  Some(format!("#show raw.line: it => {}", body))
+}
+
+/// The number of lists enclosing the current event.
+fn list_depth(event_stack: &[EventType]) -> usize {
+  event_stack
+    .iter()
+    .filter(|e| matches!(e, EventType::List | EventType::NumberedList(_)))
+    .count()
+}
+
+fn footnote_marker(name: &str) -> String {
+  format!("\x01MDBOOK_TYPST_PDF_FOOTNOTE:{}\x01", name)
+}
+
+fn email_regex() -> &'static Regex {
+  EMAIL_REGEX.get_or_init(|| Regex::new(r"(?i)^[\w.+-]+@\w+([.-]\w+)*\.\w{2,}$").unwrap())
+}
+
+/// The book `src` directory.
+fn src_dir(ctx: &RenderContext) -> Result<PathBuf, anyhow::Error> {
+  Ok(
+    ctx.root.join(
+      ctx
+        .config
+        .book
+        .src
+        .to_str()
+        .ok_or(anyhow!("src not found"))?,
+    ),
+  )
+}
+
+/// Resolve a path referenced by a chapter into a `/` separated path relative
+/// to `src`, which is also its path in the destination.
+///
+/// Paths are percent-decoded first, so `my%20diagram.png` names the file
+/// `my diagram.png`. Paths starting with `/` are relative to `src`, others to
+/// the directory of the chapter. `..` never leaves `src`.
+fn resolve_src_path(chapter_dir: &Path, path: &str) -> String {
+  let path = percent_decode_str(path).decode_utf8_lossy();
+
+  let joined = match path.strip_prefix('/') {
+    Some(path) => PathBuf::from(path),
+    None => chapter_dir.join(path.as_ref()),
+  };
+
+  let mut components = Vec::new();
+
+  for component in joined.components() {
+    match component {
+      Component::ParentDir => {
+        components.pop();
+      }
+      Component::Normal(c) => components.push(c.to_string_lossy()),
+      _ => (),
+    }
+  }
+
+  components.join("/")
+}
+
+/// Make an image referenced by a chapter available in the destination and
+/// return its path there.
+///
+/// Local images are copied from `src`, http(s) images are downloaded unless
+/// `remote-images` is disabled, in which case `None` is returned.
+fn image_path(
+  ctx: &RenderContext,
+  cfg: &Config,
+  chapter_dir: &Path,
+  dest_url: &str,
+) -> Result<Option<String>, anyhow::Error> {
+  if !(dest_url.starts_with("http://") || dest_url.starts_with("https://")) {
+    let image_path = resolve_src_path(chapter_dir, dest_url);
+
+    let src_path = src_dir(ctx)?.join(&image_path);
+    let dest_path = ctx.destination.join(&image_path);
+
+    if !dest_path.exists() {
+      let data = fs::read(&src_path)
+        .map_err(|err| anyhow!("failed to copy `{}`: {}", src_path.display(), err))?;
+
+      write_file(&dest_path, &downsample_image(cfg, data))?;
+    }
+
+    return Ok(Some(image_path));
+  }
+
+  if !cfg.remote_images {
+    tracing::warn!(
+      "Remote image `{}` is not downloaded, linking it instead",
+      dest_url
+    );
+
+    return Ok(None);
+  }
+
+  fetch_remote_image(ctx, cfg, dest_url).map(Some)
+}
+
+/// Download a remote image into the destination.
+///
+/// The file is named by the hash of its url, so later builds into the same
+/// destination reuse it.
+fn fetch_remote_image(
+  ctx: &RenderContext,
+  cfg: &Config,
+  url: &str,
+) -> Result<String, anyhow::Error> {
+  let mut image_path = format!("remote-images/{:032x}", typst::utils::hash128(url));
+
+  // Keep the extension so typst can tell svg from raster images.
+  let extension = url
+    .split(['?', '#'])
+    .next()
+    .and_then(|u| u.split_once("://"))
+    .and_then(|(_, rest)| rest.split_once('/'))
+    .and_then(|(_, path)| Path::new(path).extension())
+    .and_then(|e| e.to_str());
+
+  if let Some(extension) = extension {
+    write!(image_path, ".{}", extension.to_lowercase())?;
+  }
+
+  let dest_path = ctx.destination.join(&image_path);
+
+  if !dest_path.exists() {
+    let data = download::downloader()
+      .download_with_progress(url, &mut PrintDownload(url))
+      .map_err(|err| anyhow!("failed to download image `{}`: {}", url, err))?;
+
+    write_file(&dest_path, &downsample_image(cfg, data))?;
+  }
+
+  Ok(image_path)
+}
+
+/// A png or jpg image scaled down to `image-dpi` at its size in the document,
+/// its natural size but at most the page size, other images are unchanged.
+///
+/// The scaled image gets a pixel density, so typst keeps its natural size.
+fn downsample_image(cfg: &Config, data: Vec<u8>) -> Vec<u8> {
+  let Some(image_dpi) = cfg.image_dpi.filter(|dpi| *dpi > 0.0) else {
+    return data;
+  };
+
+  let format = match RasterFormat::detect(&data) {
+    Some(format @ (RasterFormat::Png | RasterFormat::Jpg)) => format,
+    _ => return data,
+  };
+
+  // Undecodable images are left for typst to report.
+  let Ok(image) = RasterImage::new(data.clone().into(), format) else {
+    return data;
+  };
+
+  let paper = cfg
+    .paper
+    .as_deref()
+    .and_then(|paper| Paper::from_str(paper).ok())
+    .unwrap_or(Paper::A4);
+
+  let (page_width, page_height) = match cfg.orientation {
+    Orientation::Portrait => (paper.width(), paper.height()),
+    Orientation::Landscape => (paper.height(), paper.width()),
+  };
+
+  let (width, height) = (image.width() as f64, image.height() as f64);
+
+  let dpi = image.dpi().unwrap_or(typst::visualize::Image::DEFAULT_DPI);
+
+  // Typst scales images larger than the page down to fit.
+  let shown_width = (width / dpi)
+    .min(page_width.to_inches())
+    .min(page_height.to_inches() * width / height);
+
+  let scale = image_dpi * shown_width / width;
+
+  if scale >= 1.0 {
+    return data;
+  }
+
+  let scaled_width = ((width * scale).round() as u32).max(1);
+  let scaled_height = ((height * scale).round() as u32).max(1);
+
+  let scaled = image
+    .dynamic()
+    .resize_exact(scaled_width, scaled_height, FilterType::Lanczos3);
+
+  let scaled_dpi = scaled_width as f64 * dpi / width;
+
+  let mut encoded = Vec::new();
+
+  let result = match format {
+    RasterFormat::Jpg => {
+      let mut encoder = JpegEncoder::new_with_quality(&mut encoded, 90);
+      encoder.set_pixel_density(PixelDensity::dpi(scaled_dpi.round() as u16));
+      encoder
+        .encode_image(&scaled.to_rgb8())
+        .map_err(anyhow::Error::from)
+    }
+    _ => {
+      let (pixels, color) = if scaled.color().has_alpha() {
+        (scaled.to_rgba8().into_raw(), png::ColorType::Rgba)
+      } else {
+        (scaled.to_rgb8().into_raw(), png::ColorType::Rgb)
+      };
+
+      let mut encoder = png::Encoder::new(&mut encoded, scaled_width, scaled_height);
+      encoder.set_color(color);
+      encoder.set_pixel_dims(Some(png::PixelDimensions {
+        xppu: (scaled_dpi / 0.0254).round() as u32,
+        yppu: (scaled_dpi / 0.0254).round() as u32,
+        unit: png::Unit::Meter,
+      }));
+
+      encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&pixels))
+        .map_err(anyhow::Error::from)
+    }
+  };
+
+  match result {
+    Ok(()) if encoded.len() < data.len() => encoded,
+    Ok(()) => data,
+    Err(err) => {
+      tracing::warn!("Failed to downsample image, keeping it unchanged: {}", err);
+
+      data
+    }
+  }
+}
+
+/// The language of a fenced code block, without attributes like `hl_lines`.
+fn code_block_name(info: &str) -> &str {
+  info.split(',').next().unwrap_or_default().trim()
+}
+
+/// Render a code block to an image with a `code-block-render` command, which
+/// reads the source from stdin and writes an svg or raster image to stdout.
+/// Images are cached by command and source in the destination.
+fn render_code_block(
+  ctx: &RenderContext,
+  lang: &str,
+  command: &str,
+  source: &str,
+) -> Result<String, anyhow::Error> {
+  let image_stem = format!(
+    "rendered-code-blocks/{:032x}",
+    typst::utils::hash128(&(command, source))
+  );
+
+  for extension in ["svg", "png", "jpg", "gif"] {
+    let image_path = format!("{}.{}", image_stem, extension);
+
+    if ctx.destination.join(&image_path).exists() {
+      return Ok(image_path);
+    }
+  }
+
+  let mut shell = if cfg!(windows) {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C");
+    shell
+  } else {
+    let mut shell = Command::new("sh");
+    shell.arg("-c");
+    shell
+  };
+
+  let mut child = shell
+    .arg(command)
+    .current_dir(&ctx.root)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(|err| {
+      anyhow!(
+        "failed to run `{}` for `{}` code block: {}",
+        command,
+        lang,
+        err
+      )
+    })?;
+
+  let mut stdin = child.stdin.take().ok_or(anyhow!("stdin not found"))?;
+
+  // Write the source while reading the output, a command may start writing
+  // before it has read all of its input.
+  let output = std::thread::scope(|scope| {
+    scope.spawn(move || stdin.write_all(source.as_bytes()));
+
+    child.wait_with_output()
+  })?;
+
+  if !output.status.success() {
+    return Err(anyhow!(
+      "failed to render `{}` code block with `{}`: {}",
+      lang,
+      command,
+      String::from_utf8_lossy(&output.stderr).trim()
+    ));
+  }
+
+  if output.stdout.is_empty() {
+    return Err(anyhow!(
+      "failed to render `{}` code block with `{}`: no image written to stdout",
+      lang,
+      command
+    ));
+  }
+
+  let extension = match RasterFormat::detect(&output.stdout) {
+    Some(RasterFormat::Png) => "png",
+    Some(RasterFormat::Jpg) => "jpg",
+    Some(RasterFormat::Gif) => "gif",
+    None => "svg",
+  };
+
+  let image_path = format!("{}.{}", image_stem, extension);
+
+  write_file(&ctx.destination.join(&image_path), &output.stdout)?;
+
+  Ok(image_path)
+}
+
+/// Copy a file from `src` to the same relative path in the destination.
+fn copy_src_file(ctx: &RenderContext, path: &str) -> Result<(), anyhow::Error> {
+  let src_path = src_dir(ctx)?.join(path);
+  let dest_path = ctx.destination.join(path);
+
+  if !dest_path.exists() {
+    copy_file(&src_path, &dest_path)
+      .map_err(|err| anyhow!("failed to copy `{}`: {}", src_path.display(), err))?;
+  }
+
+  Ok(())
+}
+
+/// Copy a file into the destination with [`write_file`].
+fn copy_file(src_path: &Path, dest_path: &Path) -> Result<(), anyhow::Error> {
+  write_file(dest_path, &fs::read(src_path)?)
+}
+
+/// Write a file into the destination through a temporary file renamed into
+/// place, chapters converted in parallel may write the same file at once.
+fn write_file(dest_path: &Path, data: &[u8]) -> Result<(), anyhow::Error> {
+  let dest_dir = dest_path.parent().ok_or(anyhow!("destination not found"))?;
+
+  fs::create_dir_all(dest_dir)?;
+
+  let mut file = tempfile::NamedTempFile::new_in(dest_dir)?;
+  file.write_all(data)?;
+  file.persist(dest_path)?;
+
+  Ok(())
+}
+
+/// Parser options for chapter contents, GitHub Flavored Markdown with the
+/// extensions mdBook enables, see the Markdown section of the README.
+fn options(cfg: &Config) -> Options {
+  let mut options = Options::ENABLE_STRIKETHROUGH
+    | Options::ENABLE_DEFINITION_LIST
+    | Options::ENABLE_HEADING_ATTRIBUTES
+    | Options::ENABLE_GFM
+    | Options::ENABLE_FOOTNOTES
+    | Options::ENABLE_TASKLISTS
+    | Options::ENABLE_TABLES
+    | Options::ENABLE_MATH;
+
+  if cfg.smart_punctuation {
+    options.insert(Options::ENABLE_SMART_PUNCTUATION);
+  }
+
+  options
+}
+
+/// The top level fields of the YAML front matter of a chapter with string,
+/// number or boolean values, lists of them are joined with commas like
+/// `author: [A, B]`.
+fn front_matter(cfg: &Config, content: &str) -> HashMap<String, String> {
+  match split_front_matter(content).filter(|_| cfg.front_matter) {
+    Some((fields, _)) => fields,
+    None => HashMap::new(),
+  }
+}
+
+/// The YAML front matter of a chapter, the lines between a `---` line at its
+/// very start and the next `---` or `...` line if they are a non-empty YAML
+/// mapping, and the byte offset after the closing line.
+///
+/// This is not left to pulldown-cmark's metadata blocks, which also start at
+/// a rule later in the chapter, even inside lists and quotes. A chapter that
+/// starts with a rule followed by text is no mapping and stays as it is.
+fn split_front_matter(content: &str) -> Option<(HashMap<String, String>, usize)> {
+  let mut lines = content.split_inclusive('\n');
+
+  let opening = lines.next()?;
+
+  if opening.trim_end() != "---" {
+    return None;
+  }
+
+  let start = opening.len();
+
+  let mut end = start;
+
+  for line in lines {
+    // A blank line after `---` makes it a rule.
+    if end == start && line.trim().is_empty() {
+      return None;
+    }
+
+    if matches!(line.trim_end(), "---" | "...") {
+      let fields = yaml_fields(&content[start..end]).filter(|fields| !fields.is_empty())?;
+
+      return Some((fields, end + line.len()));
+    }
+
+    end += line.len();
+  }
+
+  None
+}
+
+/// The top level fields of a YAML mapping, the part of YAML front matter
+/// uses: plain and quoted scalars, `|` and `>` block scalars and flow or
+/// block lists of scalars. Nested mappings and empty values are left out,
+/// anything that is no mapping is `None`.
+fn yaml_fields(yaml: &str) -> Option<HashMap<String, String>> {
+  let mut fields = HashMap::new();
+
+  let mut lines = yaml.lines().peekable();
+
+  while let Some(line) = lines.next() {
+    if line.trim().is_empty() || line.starts_with('#') {
+      continue;
+    }
+
+    let (key, value) = line
+      .split_once(": ")
+      .or_else(|| Some((line.strip_suffix(':')?, "")))?;
+
+    let key = yaml_scalar(key);
+
+    if key.is_empty() || key.starts_with(['-', '[', '{', ' ', '\t']) {
+      return None;
+    }
+
+    // The indented lines after a key belong to its value.
+    let mut block = Vec::new();
+
+    while let Some(line) =
+      lines.next_if(|line| line.trim().is_empty() || line.starts_with([' ', '\t']))
+    {
+      block.push(line);
+    }
+
+    let value = strip_yaml_comment(value);
+
+    let value = if value.starts_with(['|', '>']) {
+      let indent = block
+        .iter()
+        .find(|line| !line.trim().is_empty())
+        .map_or(0, |line| line.len() - line.trim_start().len());
+
+      let lines = block
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or("").trim_end());
+
+      if value.starts_with('|') {
+        lines.collect::<Vec<_>>().join("\n")
+      } else {
+        lines.collect::<Vec<_>>().join(" ")
+      }
+    } else if let Some(items) = value.strip_prefix('[').and_then(|v| v.strip_suffix(']')) {
+      items
+        .split(',')
+        .map(yaml_scalar)
+        .filter(|item| !item.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+    } else if value.is_empty() {
+      block
+        .iter()
+        .filter_map(|line| line.trim().strip_prefix("- "))
+        .map(|item| yaml_scalar(strip_yaml_comment(item)))
+        .filter(|item| !item.is_empty())
+        .collect::<Vec<_>>()
+        .join(", ")
+    } else if value.starts_with('{') {
+      String::new()
+    } else {
+      // A plain scalar continues on the indented lines.
+      let lines = std::iter::once(value).chain(block.iter().map(|line| strip_yaml_comment(line)));
+
+      yaml_scalar(
+        &lines
+          .filter(|line| !line.is_empty())
+          .collect::<Vec<_>>()
+          .join(" "),
+      )
+    };
+
+    let value = value.trim_end().to_string();
+
+    if !value.is_empty() {
+      fields.insert(key, value);
+    }
+  }
+
+  Some(fields)
+}
+
+/// A YAML value without a trailing ` # comment`, which starts after the
+/// closing quote of a quoted value.
+fn strip_yaml_comment(value: &str) -> &str {
+  let value = value.trim();
+
+  let end = match value.chars().next() {
+    Some(quote @ ('"' | '\'')) => {
+      let mut chars = value.char_indices().skip(1);
+
+      let mut end = value.len();
+
+      while let Some((index, c)) = chars.next() {
+        if quote == '"' && c == '\\' {
+          chars.next();
+        } else if c == quote && (quote == '"' || !value[index + 1..].starts_with(quote)) {
+          end = index + 1;
+
+          break;
+        } else if c == quote {
+          // A doubled `''` is an escaped quote.
+          chars.next();
+        }
+      }
+
+      end
+    }
+    _ => value.find(" #").unwrap_or(value.len()),
+  };
+
+  value[..end].trim_end()
+}
+
+/// A plain or quoted YAML scalar, `null` and `~` are empty.
+fn yaml_scalar(value: &str) -> String {
+  let value = value.trim();
+
+  if let Some(quoted) = value.strip_prefix('\'').and_then(|v| v.strip_suffix('\'')) {
+    return quoted.replace("''", "'");
+  }
+
+  let Some(quoted) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+    return match value {
+      "null" | "~" => String::new(),
+      value => value.to_string(),
+    };
+  };
+
+  let mut unquoted = String::with_capacity(quoted.len());
+
+  let mut chars = quoted.chars();
+
+  while let Some(c) = chars.next() {
+    if c != '\\' {
+      unquoted.push(c);
+
+      continue;
+    }
+
+    match chars.next() {
+      Some('n') => unquoted.push('\n'),
+      Some('t') => unquoted.push('\t'),
+      Some(c) => unquoted.push(c),
+      None => {}
+    }
+  }
+
+  unquoted
+}
+
+/// The content of a chapter with its front matter replaced by empty lines, so
+/// the Markdown lines stay where they are.
+fn strip_front_matter<'a>(cfg: &Config, content: &'a str) -> Cow<'a, str> {
+  match split_front_matter(content).filter(|_| cfg.front_matter) {
+    Some((_, end)) => {
+      Cow::Owned("\n".repeat(content[..end].matches('\n').count()) + &content[end..])
+    }
+    None => Cow::Borrowed(content),
+  }
+}
+
+/// The typst of a `typst-preamble` fenced code block if it is the first block
+/// of a chapter, and the chapter with the block replaced by empty lines.
+fn split_preamble<'a>(cfg: &Config, markdown: Cow<'a, str>) -> (Option<String>, Cow<'a, str>) {
+  let mut events = Parser::new_ext(&markdown, options(cfg)).into_offset_iter();
+
+  let range = match events.next() {
+    Some((Event::Start(Tag::CodeBlock(CodeBlockKind::Fenced(lang))), range))
+      if code_block_name(&lang) == "typst-preamble" =>
+    {
+      range
+    }
+    _ => return (None, markdown),
+  };
+
+  let preamble = events
+    .map_while(|(event, _)| match event {
+      Event::Text(text) => Some(text.into_string()),
+      _ => None,
+    })
+    .collect::<String>();
+
+  let mut stripped = "\n".repeat(markdown[..range.end].matches('\n').count());
+  stripped.push_str(&markdown[range.end..]);
+
+  (Some(preamble), Cow::Owned(stripped))
+}
+
+/// The chapter with inline footnotes like `^[text]` replaced by references to
+/// footnote definitions appended to it, outside of code, math and HTML.
+fn inline_footnotes<'a>(cfg: &Config, markdown: Cow<'a, str>) -> Cow<'a, str> {
+  if !cfg.inline_footnotes || !markdown.contains("^[") {
+    return markdown;
+  }
+
+  // Code blocks start with the range of the whole block.
+  let literal: Vec<Range<usize>> = Parser::new_ext(&markdown, options(cfg))
+    .into_offset_iter()
+    .filter_map(|(event, range)| match event {
+      Event::Start(Tag::CodeBlock(_))
+      | Event::Code(_)
+      | Event::InlineMath(_)
+      | Event::DisplayMath(_)
+      | Event::Html(_)
+      | Event::InlineHtml(_) => Some(range),
+      _ => None,
+    })
+    .collect();
+
+  let literal_end = |index: usize| {
+    literal
+      .iter()
+      .find(|range| range.contains(&index))
+      .map(|range| range.end)
+  };
+
+  let bytes = markdown.as_bytes();
+
+  let mut converted = String::new();
+
+  let mut definitions = String::new();
+
+  let mut copied = 0;
+
+  let mut index = 0;
+
+  while let Some(found) = markdown[index..].find("^[") {
+    let start = index + found;
+
+    index = start + 2;
+
+    if let Some(end) = literal_end(start) {
+      index = end.max(index);
+
+      continue;
+    }
+
+    if bytes[..start].ends_with(b"\\") {
+      continue;
+    }
+
+    // The matching bracket within the paragraph, escaped brackets and
+    // brackets in code spans do not count.
+    let mut depth = 1;
+
+    let mut end = index;
+
+    while end < bytes.len() && depth > 0 {
+      if let Some(literal_end) = literal_end(end) {
+        end = literal_end;
+
+        continue;
+      }
+
+      match bytes[end] {
+        b'\\' => end += 1,
+        b'[' => depth += 1,
+        b']' => depth -= 1,
+        b'\n'
+          if markdown[end + 1..]
+            .lines()
+            .next()
+            .is_none_or(|l| l.trim().is_empty()) =>
+        {
+          break
+        }
+        _ => {}
+      }
+
+      end += 1;
+    }
+
+    if depth > 0 {
+      continue;
+    }
+
+    let name = format!("inline-footnote-{}", definitions.lines().count() + 1);
+
+    let text = markdown[index..end - 1]
+      .split_whitespace()
+      .collect::<Vec<_>>()
+      .join(" ");
+
+    converted.push_str(&markdown[copied..start]);
+    write!(converted, "[^{}]", name).unwrap();
+
+    writeln!(definitions, "[^{}]: {}", name, text).unwrap();
+
+    copied = end;
+    index = end;
+  }
+
+  if definitions.is_empty() {
+    return markdown;
+  }
+
+  converted.push_str(&markdown[copied..]);
+  converted.push_str("\n\n");
+
+  // Blank lines keep the definitions apart from a list at the end.
+  converted.push_str(&definitions.replace('\n', "\n\n"));
+
+  Cow::Owned(converted)
+}
+
+/// Whether Markdown has nothing to show, only whitespace and comments other
+/// than `typst:` directives.
+fn is_empty_content(content: &str) -> bool {
+  Parser::new(content).all(|event| match event {
+    Event::Html(html) | Event::InlineHtml(html) => {
+      let html = html.trim();
+
+      html.starts_with("<!--") && html.ends_with("-->") && comment_directive(html).is_none()
+    }
+    Event::Start(Tag::HtmlBlock) | Event::End(TagEnd::HtmlBlock) => true,
+    _ => false,
+  })
+}
+
+/// The section number of a numbered chapter like `1.2.`, chapters listed in
+/// `appendix-chapters` and their sub chapters count with letters like `A.1.`.
+fn section_number(ctx: &RenderContext, cfg: &Config, ch: &Chapter) -> Option<String> {
+  let number = ch.number.as_ref()?;
+
+  let appendix = ctx
+    .book
+    .iter()
+    .filter_map(|item| match item {
+      BookItem::Chapter(appendix) => appendix.number.as_ref().zip(appendix.source_path.as_ref()),
+      _ => None,
+    })
+    .filter(|(_, path)| cfg.appendix_chapters.iter().any(|a| Path::new(a) == *path))
+    .enumerate()
+    .find(|(_, (appendix, _))| number.starts_with(appendix));
+
+  let Some((index, (appendix, _))) = appendix else {
+    return Some(number.to_string());
+  };
+
+  let mut section_number = appendix_letter(index);
+
+  for n in &number[appendix.len()..] {
+    write!(section_number, ".{}", n).ok()?;
+  }
+
+  section_number.push('.');
+
+  Some(section_number)
+}
+
+/// The letters of the appendix at an index, `A` to `Z`, then `AA`.
+fn appendix_letter(index: usize) -> String {
+  let mut letters = Vec::new();
+
+  let mut index = index + 1;
+
+  while index > 0 {
+    index -= 1;
+
+    letters.push((b'A' + (index % 26) as u8) as char);
+
+    index /= 26;
+  }
+
+  letters.iter().rev().collect()
+}
+
+/// The label of a chapter, its path relative to `src` without extension and
+/// with `:` between directories, like `guide:index` for `guide/index.md`.
+/// Typst labels cannot contain `/`.
+fn chapter_label(source_path: &Path) -> Option<String> {
+  let mut label = String::new();
+
+  for component in source_path.parent()?.components() {
+    if let Component::Normal(dir) = component {
+      label.push_str(dir.to_str()?);
+      label.push(':');
+    }
+  }
+
+  label.push_str(source_path.file_name()?.to_str()?.split('.').next()?);
+
+  Some(label)
+}
+
+/// The figure id of an image from its alt text, either explicit like
+/// `![Overview {#overview}](overview.png)` or `fig-` followed by the
+/// normalized alt text. Images without alt text have no id.
+fn figure_id(alt: &str) -> Option<String> {
+  if let Some(captures) = figure_id_regex().captures(alt) {
+    return Some(captures[1].to_string());
+  }
+
+  let id = mdbook::utils::normalize_id(alt.trim());
+
+  (!id.is_empty()).then(|| format!("fig-{}", id))
+}
+
+/// Collect the labels of all figures in the book up front, so links to
+/// figures in later chapters also become references.
+fn figure_labels(ctx: &RenderContext, cfg: &Config) -> HashSet<String> {
+  let mut figure_labels = HashSet::new();
+
+  for item in ctx.book.iter() {
+    let BookItem::Chapter(ref ch) = *item else {
+      continue;
+    };
+
+    let Some(label) = ch.source_path.as_deref().and_then(chapter_label) else {
+      continue;
+    };
+
+    let mut alt: Option<String> = None;
+
+    for event in Parser::new_ext(&strip_front_matter(cfg, &ch.content), options(cfg)) {
+      match event {
+        Event::Start(Tag::Image { .. }) => alt = Some(String::new()),
+        Event::Text(t) => {
+          if let Some(alt) = alt.as_mut() {
+            alt.push_str(&t);
+          }
+        }
+        Event::End(TagEnd::Image) => {
+          if let Some(id) = alt.take().as_deref().and_then(figure_id) {
+            figure_labels.insert(format!("{}.html-{}", label, id));
+          }
+        }
+        _ => (),
+      }
+    }
+  }
+
+  figure_labels
+}
+
+/// The label a relative link jumps to.
+///
+/// Links to `.md` or `.html` files point at the label of the chapter they
+/// resolve to from the linking chapter's directory, fragments point at the
+/// heading label within that chapter. Other destinations have no label.
+fn link_label(label: &str, chapter_dir: &Path, dest_url: &str) -> Option<String> {
+  let (path, fragment) = match dest_url.split_once('#') {
+    Some((path, fragment)) => (path, Some(fragment)),
+    None => (dest_url, None),
+  };
+
+  let chapter = if path.is_empty() {
+    label.to_string()
+  } else if path.ends_with(".md") || path.ends_with(".html") {
+    chapter_label(Path::new(&resolve_src_path(chapter_dir, path)))?
+  } else {
+    return None;
+  };
+
+  match fragment {
+    Some(fragment) => Some(format!("{}.html-{}", chapter, fragment)),
+    None => Some(format!("{}.html", chapter)),
+  }
+}
+
+/// Convert LaTeX style math into Typst math.
+///
+/// Typst treats a run of letters as a single identifier, so adjacent letters
+/// are separated by spaces (`mc^2` becomes `m c^2`). Commands like `\alpha`
+/// lose their backslash and braces become parentheses. The arguments of
+/// `\frac`, `\binom` and `\sqrt` become call arguments like `frac(a, b)`,
+/// `\text{..}` becomes a string and `\operatorname{lcm}` becomes `op("lcm")`.
+/// Commands unknown to typst are written as strings.
+fn convert_math(math: &str) -> String {
+  convert_math_group(math, false)
+}
+
+/// Convert LaTeX style math, `in_arg` escapes commas and semicolons, which
+/// separate the arguments of a typst function call.
+fn convert_math_group(math: &str, in_arg: bool) -> String {
+  let mut typst_math = String::with_capacity(math.len() * 2);
+
+  let mut chars = math.chars().peekable();
+
+  let mut prev_ident = false;
+
+  while let Some(ch) = chars.next() {
+    match ch {
+      '\\' => match chars.peek() {
+        Some(c) if c.is_ascii_alphabetic() => {
+          let mut command = String::new();
+
+          while let Some(c) = chars.peek().filter(|c| c.is_ascii_alphabetic()) {
+            command.push(*c);
+            chars.next();
+          }
+
+          let call = match command.as_str() {
+            "frac" | "dfrac" | "tfrac" => math_args(&mut chars, 2).map(|args| ("frac", args)),
+            "binom" => math_args(&mut chars, 2).map(|args| ("binom", args)),
+            "sqrt" => match math_option(&mut chars) {
+              Some(index) => math_args(&mut chars, 1).map(|args| {
+                (
+                  "root",
+                  std::iter::once(convert_math_group(&index, true))
+                    .chain(args)
+                    .collect(),
+                )
+              }),
+              None => math_args(&mut chars, 1).map(|args| ("sqrt", args)),
+            },
+            "operatorname" => {
+              math_arg(&mut chars).map(|arg| ("op", vec![typst_string(&math_text(&arg))]))
+            }
+            _ => None,
+          };
+
+          if let Some((name, args)) = call {
+            if prev_ident {
+              typst_math.push(' ');
+            }
+
+            write!(typst_math, "{}({})", name, args.join(", ")).unwrap();
+
+            prev_ident = false;
+
+            continue;
+          }
+
+          // Text is written as a string, which typst sets upright.
+          if matches!(command.as_str(), "text" | "textrm" | "mathrm" | "mbox") {
+            if let Some(arg) = math_arg(&mut chars) {
+              if prev_ident {
+                typst_math.push(' ');
+              }
+
+              typst_math.push_str(&typst_string(&math_text(&arg)));
+
+              prev_ident = false;
+
+              continue;
+            }
+          }
+
+          let name = match command.as_str() {
+            "cdot" => "dot.op",
+            "ldots" => "dots.h",
+            "cdots" => "dots.h.c",
+            "le" | "leq" => "lt.eq",
+            "ge" | "geq" => "gt.eq",
+            "ne" | "neq" => "eq.not",
+            "infty" => "infinity",
+            "to" | "rightarrow" => "arrow.r",
+            "leftarrow" => "arrow.l",
+            "Rightarrow" => "arrow.r.double",
+            "mathbb" => "bb",
+            "mathcal" => "cal",
+            "mathbf" => "bold",
+            "left" | "right" => "",
+            name => name,
+          };
+
+          if prev_ident {
+            typst_math.push(' ');
+          }
+
+          let math_names = MATH_NAMES.get_or_init(|| {
+            typst::math::module()
+              .scope()
+              .iter()
+              .map(|(name, _, _)| name.to_string())
+              .collect()
+          });
+
+          // Unknown commands would stop the compilation as unknown variables.
+          if name.is_empty()
+            || name
+              .split('.')
+              .next()
+              .is_some_and(|base| math_names.contains(base))
+          {
+            typst_math.push_str(name);
+
+            prev_ident = !name.is_empty();
+          } else {
+            tracing::warn!("Unknown math command `\\{}`, written as text", name);
+
+            typst_math.push_str(&typst_string(name));
+
+            prev_ident = false;
+          }
+        }
+        Some('\\') => {
+          chars.next();
+          typst_math.push_str(" \\ ");
+          prev_ident = false;
+        }
+        Some(&c) => {
+          chars.next();
+          typst_math.push('\\');
+          typst_math.push(c);
+          prev_ident = false;
+        }
+        None => typst_math.push_str("\\\\"),
+      },
+      '{' => {
+        typst_math.push('(');
+        prev_ident = false;
+      }
+      '}' => {
+        typst_math.push(')');
+        prev_ident = false;
+      }
+      '"' | '#' => {
+        typst_math.push('\\');
+        typst_math.push(ch);
+        prev_ident = false;
+      }
+      ',' | ';' if in_arg => {
+        typst_math.push('\\');
+        typst_math.push(ch);
+        prev_ident = false;
+      }
+      c if c.is_alphanumeric() => {
+        if prev_ident {
+          typst_math.push(' ');
+        }
+
+        typst_math.push(c);
+
+        prev_ident = c.is_alphabetic();
+      }
+      c => {
+        typst_math.push(c);
+        prev_ident = false;
+      }
+    }
+  }
+
+  typst_math
+}
+
+/// The next `count` arguments of a LaTeX command converted to typst.
+fn math_args(chars: &mut Peekable<Chars>, count: usize) -> Option<Vec<String>> {
+  (0..count)
+    .map(|_| math_arg(chars).map(|arg| convert_math_group(&arg, true)))
+    .collect()
+}
+
+/// The next argument of a LaTeX command as written, the content of a brace
+/// group like `{a + b}` or a single token like `2` or `\pi`.
+fn math_arg(chars: &mut Peekable<Chars>) -> Option<String> {
+  while chars.next_if(|c| c.is_whitespace()).is_some() {}
+
+  let arg = match chars.next()? {
+    '{' => {
+      let mut depth = 0;
+
+      let mut arg = String::new();
+
+      while let Some(c) = chars.next() {
+        match c {
+          '{' => depth += 1,
+          '}' if depth == 0 => break,
+          '}' => depth -= 1,
+          '\\' => {
+            arg.push(c);
+
+            if let Some(c) = chars.next() {
+              arg.push(c);
+            }
+
+            continue;
+          }
+          _ => {}
+        }
+
+        arg.push(c);
+      }
+
+      arg
+    }
+    '\\' => {
+      let mut arg = String::from('\\');
+
+      while let Some(c) = chars.next_if(|c| c.is_ascii_alphabetic()) {
+        arg.push(c);
+      }
+
+      arg
+    }
+    c => c.to_string(),
+  };
+
+  Some(arg)
+}
+
+/// The text of a LaTeX text argument like `\text{if }`, with escaped
+/// characters like `\{` and `\%` unescaped.
+fn math_text(arg: &str) -> String {
+  let mut text = String::with_capacity(arg.len());
+
+  let mut chars = arg.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    match chars.next_if(|next| c == '\\' && !next.is_ascii_alphabetic()) {
+      Some(escaped) => text.push(escaped),
+      None => text.push(c),
+    }
+  }
+
+  text
+}
+
+/// The optional argument of a LaTeX command like the index of `\sqrt[3]{x}`.
+fn math_option(chars: &mut Peekable<Chars>) -> Option<String> {
+  chars.next_if_eq(&'[')?;
+
+  let mut option = String::new();
+
+  for c in chars.by_ref() {
+    if c == ']' {
+      break;
+    }
+
+    option.push(c);
+  }
+
+  Some(option)
+}
+
+/// The directive of a HTML comment like `<!-- typst: pagebreak -->`.
+fn comment_directive(html: &str) -> Option<&str> {
+  html
+    .trim()
+    .strip_prefix("<!--")?
+    .strip_suffix("-->")?
+    .trim()
+    .strip_prefix("typst:")
+    .map(str::trim)
+}
+
+/// Convert a layout directive, `pagebreak` always starts a new page,
+/// `newpage` only when the current page is not empty and `columns 2` sets the
+/// number of columns of the following pages.
+fn convert_comment_directive(directive: &str) -> Option<String> {
+  let mut words = directive.split_whitespace();
+
+  let typst = match (words.next()?, words.next()) {
+    ("pagebreak", None) => "#pagebreak()".to_string(),
+    ("newpage", None) => "#pagebreak(weak: true)".to_string(),
+    ("columns", Some(columns)) => {
+      let columns = columns.parse::<usize>().ok().filter(|c| *c > 0)?;
+
+      format!("#set page(columns: {})", columns)
+    }
+    _ => return None,
+  };
+
+  words.next().is_none().then_some(typst)
+}
+
+/// Convert a pandoc style citation like `[@smith2020]`, `[@a; @b]` or
+/// `[@smith2020, p. 33]` into typst citations, adjacent ones are grouped.
+fn convert_citation(text: &str) -> Option<String> {
+  let inner = text.strip_prefix('[')?.strip_suffix(']')?;
+
+  inner
+    .split(';')
+    .map(|part| {
+      let part = part.trim().strip_prefix('@')?;
+
+      let (key, locator) = match part.split_once(',') {
+        Some((key, locator)) => (key.trim(), locator.trim()),
+        None => (part.trim(), ""),
+      };
+
+      if key.is_empty()
+        || !key
+          .chars()
+          .all(|c| c.is_alphanumeric() || matches!(c, '_' | '-' | ':' | '.'))
+      {
+        return None;
+      }
+
+      Some(if locator.is_empty() {
+        format!("#cite(<{}>)", key)
+      } else {
+        format!("#cite(<{}>, supplement: [{}])", key, escape_text(locator))
+      })
+    })
+    .collect::<Option<Vec<String>>>()
+    .map(|citations| citations.join(" "))
+}
+
+/// Write text into markup, escaped.
+fn write_text(cfg: &Config, content_str: &mut String, text: &str) -> std::fmt::Result {
+  let mut text = escape_text(text);
+
+  if !cfg.smart_punctuation {
+    text = escape_punctuation(&text);
+  }
+
+  // A call like `#strong[..]` takes a directly following `(` as its
+  // arguments and `.` as a field access.
+  if content_str.ends_with([']', ')']) && text.starts_with(['(', '.']) {
+    content_str.push('\\');
+  }
+
+  write!(content_str, "{}", text)
+}
+
+/// Find the first bare http(s) url in text, without trailing punctuation and
+/// unbalanced closing parentheses like in `(see https://example.com).`
+fn find_bare_url(text: &str) -> Option<(usize, &str)> {
+  let found = bare_url_regex().find(text)?;
+
+  let mut url = found.as_str();
+
+  loop {
+    url = url.trim_end_matches(['.', ',', ':', ';', '!', '?', '\'', '"']);
+
+    if url.ends_with(')') && url.matches('(').count() < url.matches(')').count() {
+      url = &url[..url.len() - 1];
+    } else {
+      break;
+    }
+  }
+
+  Some((found.start(), url))
+}
+
+/// Escape characters with a special meaning in typst markup.
+///
+/// Text may start a line after a soft break, so markup that only counts at
+/// the start of a line, like `= ` headings and `2. ` lists, is escaped there.
+fn escape_text(text: &str) -> String {
+  let mut transformed_text = String::with_capacity(text.len());
+
+  let mut line_start = true;
+
+  for (index, ch) in text.char_indices() {
+    let rest = &text[index + ch.len_utf8()..];
+
+    let escape = match ch {
+      '\\' | '#' | '$' | '`' | '*' | '_' | '<' | '>' | '@' | '[' | ']' | '~' => true,
+      // `//` and `/*` start comments.
+      '/' if rest.starts_with(['/', '*']) => true,
+      _ if line_start => is_line_start_markup(ch, rest),
+      _ => false,
+    };
+
+    if escape {
+      transformed_text.push('\\');
+    }
+
+    transformed_text.push(ch);
+
+    line_start = ch == '\n' || (line_start && matches!(ch, ' ' | '\t'));
+  }
+
+  transformed_text
+}
+
+/// Whether a line starting with `ch` followed by `rest` is a typst heading,
+/// list, numbered list or term list.
+fn is_line_start_markup(ch: char, rest: &str) -> bool {
+  let marker_end = |rest: &str| rest.is_empty() || rest.starts_with(char::is_whitespace);
+
+  match ch {
+    '=' => marker_end(rest.trim_start_matches('=')),
+    '-' | '+' | '/' => marker_end(rest),
+    '0'..='9' => rest
+      .trim_start_matches(|c: char| c.is_ascii_digit())
+      .strip_prefix('.')
+      .is_some_and(marker_end),
+    _ => false,
+  }
+}
+
+/// Escape the characters typst turns into smart quotes, dashes and ellipses
+/// in text escaped by [`escape_text`], whose escapes are kept as they are.
+fn escape_punctuation(text: &str) -> String {
+  let mut escaped = String::with_capacity(text.len());
+
+  let mut chars = text.chars();
+
+  while let Some(ch) = chars.next() {
+    match ch {
+      '\\' => {
+        escaped.push(ch);
+        escaped.extend(chars.next());
+      }
+      '-' | '"' | '\'' => {
+        escaped.push('\\');
+        escaped.push(ch);
+      }
+      _ => escaped.push(ch),
+    }
+  }
+
+  escaped.replace("...", "\\.\\.\\.")
+}
+
+/// Find the first element with the given tag name, depth first.
+fn find_element(node: &Handle, tag: &str) -> Option<Handle> {
+  for child in node.children.borrow().iter() {
+    if let NodeData::Element { name, .. } = &child.data {
+      if name.local.as_ref() == tag {
+        return Some(child.clone());
+      }
+    }
+
+    if let Some(element) = find_element(child, tag) {
+      return Some(element);
+    }
+  }
+
+  None
+}
+
+/// The text content of a node, with whitespace collapsed.
+fn node_text(node: &Handle) -> String {
+  fn collect(node: &Handle, text: &mut String) {
+    if let NodeData::Text { contents } = &node.data {
+      text.push_str(&contents.borrow());
+    }
+
+    for child in node.children.borrow().iter() {
+      collect(child, text);
+    }
+  }
+
+  let mut text = String::new();
+
+  collect(node, &mut text);
+
+  text.split_whitespace().collect::<Vec<&str>>().join(" ")
+}
+
+/// The title, icon and color of a GitHub alert kind.
+fn alert_style(kind: BlockQuoteKind) -> (&'static str, &'static str, &'static str) {
+  match kind {
+    BlockQuoteKind::Note => ("Note", "ℹ", "#0969da"),
+    BlockQuoteKind::Tip => ("Tip", "✔", "#1a7f37"),
+    BlockQuoteKind::Important => ("Important", "❢", "#8250df"),
+    BlockQuoteKind::Warning => ("Warning", "⚠", "#9a6700"),
+    BlockQuoteKind::Caution => ("Caution", "⊘", "#d1242f"),
+  }
+}
+
+/// The colored box of an alert without its body.
+fn alert_block(color: &str) -> String {
+  format!(
+    "#block(width: 100%, inset: 10pt, radius: 4pt, stroke: (left: 3pt + rgb(\"{color}\")), fill: rgb(\"{color}\").lighten(92%))"
+  )
+}
+
+/// The alert kind styling a HTML `<div>` class.
+fn div_kind(class: &str) -> Option<BlockQuoteKind> {
+  match class.to_lowercase().as_str() {
+    "note" => Some(BlockQuoteKind::Note),
+    "tip" => Some(BlockQuoteKind::Tip),
+    "important" => Some(BlockQuoteKind::Important),
+    "warning" => Some(BlockQuoteKind::Warning),
+    "caution" => Some(BlockQuoteKind::Caution),
+    _ => None,
+  }
+}
+
+/// Convert a HTML `<table>` element into a typst table.
+fn convert_html_table(cfg: &Config, table: &Handle, table_style: &str) -> String {
+  fn collect_rows(node: &Handle, rows: &mut Vec<Handle>) {
+    for child in node.children.borrow().iter() {
+      if let NodeData::Element { name, .. } = &child.data {
+        match name.local.as_ref() {
+          "tr" => rows.push(child.clone()),
+          "table" => (),
+          _ => collect_rows(child, rows),
+        }
+      }
+    }
+  }
+
+  let mut rows = Vec::new();
+
+  collect_rows(table, &mut rows);
+
+  let mut columns = 0;
+
+  let mut cells = String::new();
+
+  for (i, row) in rows.iter().enumerate() {
+    let mut row_columns = 0;
+
+    for cell in row.children.borrow().iter() {
+      let NodeData::Element { name, attrs, .. } = &cell.data else {
+        continue;
+      };
+
+      let header = match name.local.as_ref() {
+        "th" => true,
+        "td" => false,
+        _ => continue,
+      };
+
+      let span = |attr: &str| {
+        attrs
+          .borrow()
+          .iter()
+          .find(|a| a.name.local.as_ref() == attr)
+          .and_then(|a| a.value.trim().parse::<usize>().ok())
+          .filter(|span| *span > 1)
+      };
+
+      let colspan = span("colspan");
+      let rowspan = span("rowspan");
+
+      row_columns += colspan.unwrap_or(1);
+
+      let mut cell_args = Vec::new();
+
+      if let Some(colspan) = colspan {
+        cell_args.push(format!("colspan: {}", colspan));
+      }
+
+      if let Some(rowspan) = rowspan {
+        cell_args.push(format!("rowspan: {}", rowspan));
+      }
+
+      let text = escape_text(&node_text(cell));
+
+      let body = if header && !text.is_empty() {
+        format!("[#strong[{}]]", text)
+      } else {
+        format!("[{}]", text)
+      };
+
+      if cell_args.is_empty() {
+        writeln!(cells, "  {},", body).unwrap();
+      } else {
+        writeln!(cells, "  table.cell({}){},", cell_args.join(", "), body).unwrap();
+      }
+    }
+
+    // Rows after the first may be shortened by cells spanning multiple rows.
+    if i == 0 || row_columns > columns {
+      columns = row_columns;
+    }
+  }
+
+  format!(
+    "#table(\n  columns: {},\n{}{})\n",
+    table_columns(cfg, columns.max(1)),
+    table_style,
+    cells
+  )
+}
+
+/// Convert a HTML/CSS length into a typst length, a unitless length is taken
+/// as pixels.
+fn html_length(length: &str) -> Option<String> {
+  let length = length.trim();
+
+  let pixels = length.strip_suffix("px").unwrap_or(length).trim();
+
+  if let Ok(pixels) = pixels.parse::<f64>() {
+    // CSS pixels are 1/96 inch, typst points 1/72 inch.
+    Some(format!("{}pt", pixels * 0.75))
+  } else if length_regex().is_match(length) {
+    Some(length.to_string())
+  } else {
+    None
+  }
+}
+
+/// Find the value of a property in a CSS style attribute.
+fn css_property(style: &str, name: &str) -> Option<String> {
+  style.split(';').find_map(|declaration| {
+    let (property, value) = declaration.split_once(':')?;
+
+    (property.trim().eq_ignore_ascii_case(name)).then(|| value.trim().to_string())
+  })
+}
+
+/// Quote a string as a typst string literal.
+fn typst_string(s: &str) -> String {
+  format!("\"{}\"", s.replace('\\', r"\\").replace('"', r#"\""#))
+}
+
+/// Quote strings as a typst array of string literals.
+fn typst_array(items: &[String]) -> String {
+  match items {
+    [] => "()".to_string(),
+    [item] => format!("({},)", typst_string(item)),
+    items => format!(
+      "({})",
+      items
+        .iter()
+        .map(|i| typst_string(i))
+        .collect::<Vec<String>>()
+        .join(", ")
+    ),
+  }
+}
+
+/// Matches http(s) urls in text, up to whitespace or markup like `<`.
+fn bare_url_regex() -> &'static Regex {
+  BARE_URL_REGEX.get_or_init(|| Regex::new(r#"\bhttps?://[^\s<>\[\]"`]+"#).unwrap())
+}
+
+/// Matches typst lengths like `2.5cm`, `12pt` or `10%`.
+fn length_regex() -> &'static Regex {
+  LENGTH_REGEX.get_or_init(|| Regex::new(r"^\d+(\.\d+)?(pt|mm|cm|in|em|%)$").unwrap())
+}
+
+/// Matches an explicit figure id like `{#overview}` at the end of alt text,
+/// also after the `#` was escaped.
+fn figure_id_regex() -> &'static Regex {
+  FIGURE_ID_REGEX.get_or_init(|| Regex::new(r"\s*\{\\?#([\w-]+)\}\s*$").unwrap())
+}
+
+/// Matches mdBook directives like `{{#include file.rs}}`.
+fn directive_regex() -> &'static Regex {
+  DIRECTIVE_REGEX.get_or_init(|| Regex::new(r"\{\{#[^}]*\}\}").unwrap())
+}
+
+/// Remove `{{#...}}` directives left over by a missing preprocessor.
+fn strip_directives<'a>(text: &'a str, label: &str) -> Cow<'a, str> {
+  for directive in directive_regex().find_iter(text) {
+    tracing::warn!(
+      "Unresolved directive `{}` in `{}`, is the preprocessor enabled?",
+      directive.as_str(),
+      label
+    );
+  }
+
+  directive_regex().replace_all(text, "")
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use mdbook::book::Book;
+
+  /// Convert the Markdown of a chapter `chapter.md` with default options.
+  fn convert(markdown: &str) -> String {
+    convert_with(&Config::default(), markdown)
+  }
+
+  fn convert_with(cfg: &Config, markdown: &str) -> String {
+    convert_positions(cfg, markdown).0
+  }
+
+  /// The typst and the pairs of typst and Markdown offsets of the events.
+  fn convert_positions(cfg: &Config, markdown: &str) -> (String, Vec<(usize, usize)>) {
+    let root = tempfile::tempdir().unwrap();
+
+    let ctx = RenderContext::new(
+      root.path(),
+      Book::new(),
+      mdbook::Config::default(),
+      root.path().join("book"),
+    );
+
+    let mut positions = Vec::new();
+
+    let typst = convert_content(
+      &ctx,
+      cfg,
+      markdown,
+      "chapter",
+      Path::new(""),
+      "",
+      &HashSet::new(),
+      &mut positions,
+    )
+    .unwrap();
+
+    (typst, positions)
+  }
+
+  #[test]
+  fn escape_text_markup() {
+    assert_eq!(escape_text(r"C:\path"), r"C:\\path");
+    assert_eq!(escape_text("array[0]"), r"array\[0\]");
+    assert_eq!(
+      escape_text("#1 *a* _b_ @c $d$ <e>"),
+      r"\#1 \*a\* \_b\_ \@c \$d\$ \<e\>"
+    );
+    assert_eq!(
+      escape_text("a // b /* c */ d/e ~f"),
+      r"a \// b \/\* c \*/ d/e \~f"
+    );
+    assert_eq!(escape_text("= Title"), r"\= Title");
+    assert_eq!(
+      escape_text("2. item\n- b\n+ c\n/ d: e"),
+      "\\2. item\n\\- b\n\\+ c\n\\/ d: e"
+    );
+    assert_eq!(escape_text("a = b, 2.5 - 1. -x"), "a = b, 2.5 - 1. -x");
+  }
+
+  #[test]
+  fn convert_escaped_prose() {
+    assert_eq!(convert(r"Open C:\path"), "Open C:\\\\path\n\n");
+    assert_eq!(
+      convert("Read array[0] first"),
+      "Read array\\[0\\] first\n\n"
+    );
+    assert_eq!(
+      convert("a // b\n= c\n2. d\n\\- e"),
+      "a \\// b\n\\= c\n\\2. d\n\\- e\n\n"
+    );
+  }
+
+  #[test]
+  fn convert_math_text() {
+    assert_eq!(convert_math(r"x \text{if } y"), r#"x "if " y"#);
+    assert_eq!(convert_math(r"\mathrm{d}x"), r#""d"x"#);
+    assert_eq!(convert_math(r"\text{50\% \{off\}}"), r#""50% {off}""#);
+  }
+
+  #[test]
+  fn convert_math_operatorname() {
+    assert_eq!(
+      convert_math(r"\operatorname{lcm}(a, b)"),
+      r#"op("lcm")(a, b)"#
+    );
+  }
+
+  #[test]
+  fn convert_math_unknown_command() {
+    assert_eq!(
+      convert_math(r"\foo + \alpha \le \beta"),
+      r#""foo" + alpha lt.eq beta"#
+    );
+  }
+
+  #[test]
+  fn convert_math_commands() {
+    assert_eq!(convert_math(r"mc^2"), "m c^2");
+    assert_eq!(convert_math(r"\alpha \le \beta"), r"alpha lt.eq beta");
+    assert_eq!(convert_math(r"x_{i+1}"), "x_(i+1)");
+  }
+
+  #[test]
+  fn convert_math_frac() {
+    assert_eq!(convert_math(r"\frac{a}{b}"), "frac(a, b)");
+    assert_eq!(convert_math(r"\frac12"), "frac(1, 2)");
+    assert_eq!(
+      convert_math(r"\frac{\frac{a}{b}}{c}"),
+      "frac(frac(a, b), c)"
+    );
+    assert_eq!(convert_math(r"\frac{a,b}{\pi}"), r"frac(a\,b, pi)");
+    assert_eq!(convert_math(r"x = \dfrac {1} {n}"), "x = frac(1, n)");
+  }
+
+  #[test]
+  fn convert_math_binom_sqrt() {
+    assert_eq!(convert_math(r"\binom{n}{k}"), "binom(n, k)");
+    assert_eq!(convert_math(r"\sqrt{x^2 + 1}"), "sqrt(x^2 + 1)");
+    assert_eq!(convert_math(r"\sqrt[n]{x}"), "root(n, x)");
+    assert_eq!(convert_math(r"a\sqrt{2}"), "a sqrt(2)");
+  }
+
+  #[test]
+  fn convert_alerts() {
+    for (kind, title, color) in [
+      ("NOTE", "ℹ Note", "#0969da"),
+      ("TIP", "✔ Tip", "#1a7f37"),
+      ("IMPORTANT", "❢ Important", "#8250df"),
+      ("WARNING", "⚠ Warning", "#9a6700"),
+      ("CAUTION", "⊘ Caution", "#d1242f"),
+    ] {
+      let typst = convert(&format!("> [!{}]\n> Useful", kind));
+
+      assert!(
+        typst.starts_with(&format!(
+          "#block(width: 100%, inset: 10pt, radius: 4pt, stroke: (left: 3pt + rgb(\"{color}\")), fill: rgb(\"{color}\").lighten(92%))[#text(fill: rgb(\"{color}\"))[*{title}*]"
+        )),
+        "{}",
+        typst
+      );
+      assert!(typst.contains("\n\nUseful\n\n]"), "{}", typst);
+    }
+  }
+
+  #[test]
+  fn convert_plain_quote() {
+    assert_eq!(
+      convert("> Just a quote"),
+      "#quote(block: true)[Just a quote\n\n]\n"
+    );
+  }
+
+  #[test]
+  fn convert_alerts_disabled() {
+    let cfg = Config {
+      alerts: false,
+      ..Config::default()
+    };
+
+    assert!(convert_with(&cfg, "> [!NOTE]\n> Useful").starts_with("#quote(block: true)["));
+  }
+
+  #[test]
+  fn resolve_encoded_src_path() {
+    let dir = Path::new("guide");
+
+    assert_eq!(
+      resolve_src_path(dir, "my%20diagram.png"),
+      "guide/my diagram.png"
+    );
+    assert_eq!(resolve_src_path(dir, "caf%C3%A9.png"), "guide/café.png");
+  }
+
+  #[test]
+  fn resolve_unencoded_src_path() {
+    let dir = Path::new("guide");
+
+    assert_eq!(
+      resolve_src_path(dir, "my diagram.png"),
+      "guide/my diagram.png"
+    );
+    assert_eq!(resolve_src_path(dir, "./img/a.png"), "guide/img/a.png");
+    assert_eq!(resolve_src_path(dir, "../img/a.png"), "img/a.png");
+    assert_eq!(resolve_src_path(dir, "/img/a.png"), "img/a.png");
+  }
+
+  #[test]
+  fn resolve_out_of_root_src_path() {
+    let dir = Path::new("guide");
+
+    assert_eq!(resolve_src_path(dir, "../../../etc/passwd"), "etc/passwd");
+    assert_eq!(
+      resolve_src_path(dir, "%2E%2E/%2E%2E/secret.png"),
+      "secret.png"
+    );
+    assert_eq!(resolve_src_path(Path::new(""), "/../a.png"), "a.png");
+  }
+
+  #[test]
+  fn convert_heading_with_code() {
+    assert_eq!(
+      convert("## The `Vec<T>` type"),
+      "#heading(level: 2, outlined: false)[The #raw(\"Vec<T>\") type] <chapter.html-the-vect-type>\n\n"
+    );
+    assert!(
+      convert("## The `a]*#` type").contains("[The #raw(\"a]*#\") type] <chapter.html-the-a-type>")
+    );
+  }
+
+  #[test]
+  fn chapter_labels() {
+    assert_eq!(chapter_label(Path::new("intro.md")).unwrap(), "intro");
+    assert_eq!(
+      chapter_label(Path::new("guide/index.md")).unwrap(),
+      "guide:index"
+    );
+    assert_eq!(chapter_label(Path::new("a/b/c.md")).unwrap(), "a:b:c");
+  }
+
+  #[test]
+  fn chapter_labels_do_not_collide() {
+    let labels: HashSet<String> = [
+      "index.md",
+      "guide/index.md",
+      "reference/index.md",
+      "guide/reference/index.md",
+    ]
+    .into_iter()
+    .map(|path| chapter_label(Path::new(path)).unwrap())
+    .collect();
+
+    assert_eq!(labels.len(), 4);
+  }
+
+  #[test]
+  fn link_labels() {
+    let dir = Path::new("guide");
+
+    assert_eq!(
+      link_label("guide:intro", dir, "#setup").unwrap(),
+      "guide:intro.html-setup"
+    );
+    assert_eq!(
+      link_label("guide:intro", dir, "index.md").unwrap(),
+      "guide:index.html"
+    );
+    assert_eq!(
+      link_label("guide:intro", dir, "../index.html#a").unwrap(),
+      "index.html-a"
+    );
+    assert_eq!(
+      link_label("guide:intro", dir, "sub/page.md").unwrap(),
+      "guide:sub:page.html"
+    );
+    assert_eq!(
+      link_label("guide:intro", dir, "/reference/index.md").unwrap(),
+      "reference:index.html"
+    );
+    assert_eq!(link_label("guide:intro", dir, "image.png"), None);
+  }
+
+  #[test]
+  fn convert_table_cell_with_code_and_link() {
+    let typst = convert("| a | b |\n|---|---|\n| `x[0]` and [link](https://e.com) | c |");
+
+    assert!(
+      typst.contains("[#raw(\"x[0]\") and #link(\"https://e.com\")[link]],\n[c],\n)"),
+      "{}",
+      typst
+    );
+    assert!(convert("| a |\n|---|\n| x] |").contains("[x\\]],"));
+  }
+
+  #[test]
+  fn convert_rule_in_quote() {
+    assert_eq!(
+      convert("> a\n>\n> ---\n>\n> b"),
+      "#quote(block: true)[a\n\n#line(length: 100%)\n\nb\n\n]\n"
+    );
+  }
+
+  #[test]
+  fn convert_rule_in_list() {
+    assert!(convert("- a\n\n  ---\n\n  b\n- c")
+      .starts_with("- a\n\n  #line(length: 100%)\n\n  b\n\n- c\n"));
+  }
+
+  #[test]
+  fn convert_footnote_with_markup_name() {
+    assert_eq!(
+      convert("See[^a#*].\n\n[^a#*]: x"),
+      "See#footnote[x] <chapter.html-fn-a>.\n\n"
+    );
+  }
+
+  #[test]
+  fn footnote_positions() {
+    let markdown = "Text[^a] and more.\n\n[^a]: A *noted* fact.\n";
+
+    let (typst, positions) = convert_positions(&Config::default(), markdown);
+
+    assert!(typst.starts_with("Text#footnote[A #emph[noted] fact.] <chapter.html-fn-a> and more."));
+
+    for word in ["noted", " fact.", " and more."] {
+      let offset = typst.find(word).unwrap();
+
+      assert!(
+        positions.contains(&(offset, markdown.find(word).unwrap())),
+        "{} {:?}",
+        word,
+        positions
+      );
+    }
+  }
+
+  #[test]
+  fn front_matter_fields() {
+    let content = "---\ntitle: \"Intro: the basics\"\nauthor: [Jane, John]\nversion: 2\ntypst-preamble: |\n  #set par(justify: false)\n  #set text(red)\n---\n# Intro\n";
+
+    let fields = front_matter(&Config::default(), content);
+
+    assert_eq!(fields["title"], "Intro: the basics");
+    assert_eq!(fields["author"], "Jane, John");
+    assert_eq!(fields["version"], "2");
+    assert_eq!(
+      fields["typst-preamble"],
+      "#set par(justify: false)\n#set text(red)"
+    );
+    assert_eq!(
+      strip_front_matter(&Config::default(), content),
+      "\n\n\n\n\n\n\n\n# Intro\n"
+    );
+  }
+
+  #[test]
+  fn front_matter_yaml_forms() {
+    let content = "---\n# comment\ntitle: 'It''s here' # quoted\nauthor:\n  - Jane\n  - \"John \\\"J\\\"\"\nsummary: >\n  Folded\n  text\nmeta:\n  draft: true\ndate: ~\n---\n";
+
+    let fields = front_matter(&Config::default(), content);
+
+    assert_eq!(fields["title"], "It's here");
+    assert_eq!(fields["author"], "Jane, John \"J\"");
+    assert_eq!(fields["summary"], "Folded text");
+    assert!(!fields.contains_key("meta"));
+    assert!(!fields.contains_key("date"));
+  }
+
+  #[test]
+  fn front_matter_author_without_heading() {
+    let root = tempfile::tempdir().unwrap();
+
+    let ctx = RenderContext::new(
+      root.path(),
+      Book::new(),
+      mdbook::Config::default(),
+      root.path().join("book"),
+    );
+
+    let ch = Chapter::new(
+      "Notes",
+      "---\nauthor: Jane\n---\nNo heading here.\n".to_string(),
+      "notes.md",
+      vec![],
+    );
+
+    let typst = convert_book_item(
+      &ctx,
+      &Config::default(),
+      &BookItem::Chapter(ch),
+      &HashSet::new(),
+    )
+    .unwrap()
+    .typst;
+
+    assert!(typst.contains("\n#emph[Jane]\n"), "{}", typst);
+  }
+
+  #[test]
+  fn front_matter_thematic_break() {
+    // A rule followed by a paragraph with a setext underline.
+    for content in [
+      "---\nSome text\n---\n",
+      "---\n\ntitle: no front matter\n---\n",
+      "---\n---\nText\n",
+      "---\n- a\n- b\n---\n",
+    ] {
+      assert!(
+        front_matter(&Config::default(), content).is_empty(),
+        "{}",
+        content
+      );
+      assert_eq!(strip_front_matter(&Config::default(), content), content);
+    }
+
+    assert!(convert("---\nSome text\n---\n").starts_with("#line(length: 100%)"));
+  }
+
+  #[test]
+  fn front_matter_disabled() {
+    let cfg = Config {
+      front_matter: false,
+      ..Config::default()
+    };
+
+    let content = "---\ntitle: Intro\n---\n";
+
+    assert!(front_matter(&cfg, content).is_empty());
+    assert_eq!(strip_front_matter(&cfg, content), content);
+  }
+
+  #[test]
+  fn link_to_headingless_chapter() {
+    let foreword = Chapter::new("Foreword", "Pre".to_string(), "foreword.md", vec![]);
+    let intro = Chapter::new(
+      "Intro",
+      "# Intro\n\nSee [the foreword](foreword.md).".to_string(),
+      "intro.md",
+      vec![],
+    );
+
+    let root = tempfile::tempdir().unwrap();
+
+    let ctx = RenderContext::new(
+      root.path(),
+      Book::new(),
+      mdbook::Config::default(),
+      root.path().join("book"),
+    );
+
+    let convert_chapter = |ch: Chapter| {
+      convert_book_item(
+        &ctx,
+        &Config::default(),
+        &BookItem::Chapter(ch),
+        &HashSet::new(),
+      )
+      .unwrap()
+      .typst
+    };
+
+    let foreword = convert_chapter(foreword);
+
+    assert!(
+      foreword.starts_with("#{\n  show heading: none\n"),
+      "{}",
+      foreword
+    );
+    assert!(
+      foreword.contains("[Foreword]\n} <foreword.html>\nPre"),
+      "{}",
+      foreword
+    );
+
+    let intro = convert_chapter(intro);
+    assert!(
+      intro.contains("See #link(<foreword.html>)[the foreword]"),
+      "{}",
+      intro
+    );
+  }
+
+  #[test]
+  fn draft_chapter_placeholder() {
+    let root = tempfile::tempdir().unwrap();
+
+    let ctx = RenderContext::new(
+      root.path(),
+      Book::new(),
+      mdbook::Config::default(),
+      root.path().join("book"),
+    );
+
+    let cfg = Config {
+      draft_chapters: DraftChapters::Placeholder,
+      ..Config::default()
+    };
+
+    let draft = Chapter::new_draft("Use *C* // later", vec![]);
+
+    let typst = convert_book_item(&ctx, &cfg, &BookItem::Chapter(draft), &HashSet::new())
+      .unwrap()
+      .typst;
+
+    assert!(
+      typst.starts_with(
+        "#heading(numbering: none, level: 1, outlined: true)[#\"Use *C* // later\"]\n\n#emph[This chapter is a draft.]"
+      ),
+      "{}",
+      typst
+    );
+  }
+
+  #[test]
+  fn part_title_and_separator() {
+    let root = tempfile::tempdir().unwrap();
+
+    let ctx = RenderContext::new(
+      root.path(),
+      Book::new(),
+      mdbook::Config::default(),
+      root.path().join("book"),
+    );
+
+    let convert_item = |cfg: &Config, item: BookItem| {
+      convert_book_item(&ctx, cfg, &item, &HashSet::new())
+        .unwrap()
+        .typst
+    };
+
+    let part = convert_item(
+      &Config::default(),
+      BookItem::PartTitle("Part *1* // intro".to_string()),
+    );
+    assert!(
+      part.contains("outlined: true)[#\"Part *1* // intro\"]"),
+      "{}",
+      part
+    );
+
+    assert_eq!(convert_item(&Config::default(), BookItem::Separator), "");
+
+    let cfg = Config {
+      chapter_pagebreak: false,
+      ..Config::default()
+    };
+    assert_eq!(
+      convert_item(&cfg, BookItem::Separator),
+      "#line(length: 100%)\n"
+    );
+  }
+
+  #[test]
+  fn code_block_hl_lines() {
+    assert_eq!(
+      parse_hl_lines("hl_lines=\"2-4 7 1-100000000 5-3 x\""),
+      vec![2..=4, 7..=7, 1..=100000000]
+    );
+
+    let typst = convert("```rust,hl_lines=2-4 7\nfn main() {}\n```\n");
+
+    assert!(
+      typst.contains("if (it.number >= 2 and it.number <= 4) or it.number == 7 {"),
+      "{}",
+      typst
+    );
+  }
 }
@@ -3,53 +3,72 @@ use codespan_reporting::diagnostic::{Diagnostic, Label};
 use codespan_reporting::term;
 use ecow::eco_format;
 use std::fs;
+use std::path::Path;
 use typst::diag::Warned;
-use typst::diag::{At, Severity, SourceDiagnostic, StrResult};
+use typst::diag::{At, Severity, SourceDiagnostic, SourceResult, StrResult};
 use typst::foundations::Datetime;
 use typst::foundations::Smart;
+use typst::layout::PageRanges;
+use typst::model::Document;
 use typst::syntax::{FileId, Source, Span};
 use typst::{World, WorldExt};
 use typst_pdf::{PdfOptions, PdfStandards};
 
-use crate::args::{DiagnosticFormat, SharedArgs};
+use crate::args::{DiagnosticFormat, OutputFormat, PdfStandard, SharedArgs};
+use crate::convert::SourceMap;
 use crate::terminal;
 use crate::world::SystemWorld;
 
 type CodespanResult<T> = Result<T, CodespanError>;
 type CodespanError = codespan_reporting::files::Error;
 
-pub fn export_pdf(args: SharedArgs) -> StrResult<()> {
-  let world = SystemWorld::new(&args).map_err(|err| eco_format!("{err}"))?;
+pub fn export(args: SharedArgs) -> StrResult<()> {
+  let world = setup(&args)?;
 
+  compile(&world, &args)
+}
+
+/// Creates the world that [`compile`] runs in.
+pub fn setup(args: &SharedArgs) -> StrResult<SystemWorld> {
+  SystemWorld::new(args).map_err(|err| eco_format!("{err}"))
+}
+
+/// Compiles the world's main file and writes all requested outputs.
+pub fn compile(world: &SystemWorld, args: &SharedArgs) -> StrResult<()> {
   tracing::info!("Starting compilation");
 
   let start = std::time::Instant::now();
 
   // Check if main file can be read and opened.
   if let Err(errors) = world.source(world.main()).at(Span::detached()) {
-    print_diagnostics(&world, &errors, &[], DiagnosticFormat::Human)
-      .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+    print_diagnostics(
+      world,
+      &args.source_map,
+      &errors,
+      &[],
+      DiagnosticFormat::Human,
+    )
+    .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
 
-    return Err(eco_format!("export_pdf failed"));
+    return Err(eco_format!("export failed"));
   }
 
-  let Warned { output, warnings } = typst::compile(&world);
+  let (warnings, result) = terminal::spin("compiling", || {
+    let Warned { output, warnings } = typst::compile(world);
 
-  let result = output.and_then(|document| {
-    let options = PdfOptions {
-      ident: Smart::Auto,
-      timestamp: convert_datetime(chrono::Utc::now()),
-      page_ranges: None,
-      standards: pdf_standards().at(Span::detached())?,
-    };
-
-    let buffer = typst_pdf::pdf(&document, &options)?;
+    let result = output.and_then(|document| {
+      for (format, output) in &args.outputs {
+        match format {
+          OutputFormat::Pdf => export_pdf(&document, args, output)?,
+          OutputFormat::Png => export_image(&document, args, output, ImageExportFormat::Png)?,
+          OutputFormat::Svg => export_image(&document, args, output, ImageExportFormat::Svg)?,
+        }
+      }
 
-    fs::write(args.output, buffer)
-      .map_err(|err| eco_format!("failed to write PDF file ({err})"))
-      .at(Span::detached())?;
+      Ok(())
+    });
 
-    Ok(())
+    (warnings, result)
   });
 
   match result {
@@ -58,23 +77,145 @@ pub fn export_pdf(args: SharedArgs) -> StrResult<()> {
 
       tracing::info!("Compilation succeeded in {duration:?}");
 
-      print_diagnostics(&world, &[], &warnings, DiagnosticFormat::Human)
-        .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+      print_diagnostics(
+        world,
+        &args.source_map,
+        &[],
+        &warnings,
+        DiagnosticFormat::Human,
+      )
+      .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+
+      if args.deny_warnings && !warnings.is_empty() {
+        return Err(eco_format!(
+          "export failed, {} warnings with deny-warnings enabled",
+          warnings.len()
+        ));
+      }
     }
     Err(errors) => {
-      print_diagnostics(&world, &errors, &[], DiagnosticFormat::Human)
-        .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
+      print_diagnostics(
+        world,
+        &args.source_map,
+        &errors,
+        &[],
+        DiagnosticFormat::Human,
+      )
+      .map_err(|err| eco_format!("failed to print diagnostics ({err})"))?;
 
-      return Err(eco_format!("export_pdf failed"));
+      return Err(eco_format!("export failed"));
     }
   }
 
   Ok(())
 }
 
+/// Export to a PDF.
+fn export_pdf(document: &Document, args: &SharedArgs, output: &Path) -> SourceResult<()> {
+  let options = PdfOptions {
+    ident: Smart::Auto,
+    timestamp: convert_datetime(args.creation_timestamp.unwrap_or_else(chrono::Utc::now)),
+    page_ranges: page_ranges(args),
+    standards: pdf_standards(&args.pdf_standard).at(Span::detached())?,
+  };
+
+  let buffer = typst_pdf::pdf(document, &options)?;
+
+  fs::write(output, buffer)
+    .map_err(|err| eco_format!("failed to write PDF file ({err})"))
+    .at(Span::detached())?;
+
+  Ok(())
+}
+
+/// An image format to export in.
+#[derive(Clone, Copy)]
+enum ImageExportFormat {
+  Png,
+  Svg,
+}
+
+/// Export to one or multiple images, one file per page.
+fn export_image(
+  document: &Document,
+  args: &SharedArgs,
+  output: &Path,
+  fmt: ImageExportFormat,
+) -> SourceResult<()> {
+  let output = output.to_string_lossy();
+
+  let page_ranges = page_ranges(args);
+
+  let pages: Vec<_> = document
+    .pages
+    .iter()
+    .enumerate()
+    .filter(|(i, _)| {
+      page_ranges
+        .as_ref()
+        .is_none_or(|ranges| ranges.includes_page_index(*i))
+    })
+    .collect();
+
+  if pages.len() > 1 && !is_output_template(&output) {
+    return Err(eco_format!(
+      "cannot export multiple images without a page number template ({{p}}, {{0p}}) in the output path"
+    ))
+    .at(Span::detached());
+  }
+
+  for (i, page) in pages {
+    let path = output_template(&output, i + 1, document.pages.len());
+
+    let buffer = match fmt {
+      ImageExportFormat::Png => typst_render::render(page, args.ppi / 72.0)
+        .encode_png()
+        .map_err(|err| eco_format!("failed to encode PNG file ({err})"))
+        .at(Span::detached())?,
+      ImageExportFormat::Svg => typst_svg::svg(page).into_bytes(),
+    };
+
+    fs::write(&path, buffer)
+      .map_err(|err| eco_format!("failed to write image file {path} ({err})"))
+      .at(Span::detached())?;
+  }
+
+  Ok(())
+}
+
+/// Whether the output path contains a page number template.
+fn is_output_template(output: &str) -> bool {
+  ["{p}", "{0p}"].iter().any(|t| output.contains(t))
+}
+
+/// Expand the `{p}`, `{0p}` and `{t}` tokens of an output path template.
+fn output_template(output: &str, this_page: usize, total_pages: usize) -> String {
+  // Find the base 10 width of number `i`
+  fn width(i: usize) -> usize {
+    1 + i.checked_ilog10().unwrap_or(0) as usize
+  }
+
+  output
+    .replace("{p}", &this_page.to_string())
+    .replace(
+      "{0p}",
+      &format!("{:0width$}", this_page, width = width(total_pages)),
+    )
+    .replace("{t}", &total_pages.to_string())
+}
+
+/// The page ranges to export, all pages if unspecified.
+fn page_ranges(args: &SharedArgs) -> Option<PageRanges> {
+  args
+    .pages
+    .as_ref()
+    .map(|pages| PageRanges::new(pages.iter().map(|p| p.0.clone()).collect()))
+}
+
 /// Print diagnostic messages to the terminal.
 pub fn print_diagnostics(
   world: &SystemWorld,
+  source_map: &SourceMap,
   errors: &[SourceDiagnostic],
   warnings: &[SourceDiagnostic],
   diagnostic_format: DiagnosticFormat,
@@ -98,6 +239,7 @@ pub fn print_diagnostics(
         .hints
         .iter()
         .map(|e| (eco_format!("hint: {e}")).into())
+        .chain(markdown_location(world, source_map, diagnostic.span))
         .collect(),
     )
     .with_labels(label(world, diagnostic.span).into_iter().collect());
@@ -118,6 +260,22 @@ pub fn print_diagnostics(
   Ok(())
 }
 
+/// Where in the Markdown of the book a span of the converted chapters is.
+fn markdown_location(world: &SystemWorld, source_map: &SourceMap, span: Span) -> Option<String> {
+  if span.id()? != world.main() {
+    return None;
+  }
+
+  let (name, path, line) = source_map.locate(world.range(span)?.start)?;
+
+  Some(format!(
+    "in chapter `{}` ({}) near line {}",
+    name,
+    path.display(),
+    line
+  ))
+}
+
 /// Create a label for a span.
 fn label(world: &SystemWorld, span: Span) -> Option<Label<FileId>> {
   Some(Label::primary(span.id()?, world.range(span)?))
@@ -182,8 +340,14 @@ impl<'a> codespan_reporting::files::Files<'a> for SystemWorld {
 }
 
 /// The PDF standards to try to conform with.
-fn pdf_standards() -> StrResult<PdfStandards> {
-  let list = vec![];
+fn pdf_standards(pdf_standard: &[PdfStandard]) -> StrResult<PdfStandards> {
+  let list = pdf_standard
+    .iter()
+    .map(|s| match s {
+      PdfStandard::V_1_7 => typst_pdf::PdfStandard::V_1_7,
+      PdfStandard::A_2b => typst_pdf::PdfStandard::A_2b,
+    })
+    .collect::<Vec<_>>();
 
   PdfStandards::new(&list)
 }
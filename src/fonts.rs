@@ -0,0 +1,77 @@
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+use typst::text::FontBook;
+use typst_kit::fonts::Fonts;
+
+use crate::args::FontArgs;
+
+/// The fonts discovered so far in this process, by the arguments they were
+/// discovered with.
+static DISCOVERED: OnceLock<Mutex<HashMap<FontArgs, Arc<Fonts>>>> = OnceLock::new();
+
+/// Searches for fonts in the configured font paths and, unless ignored, in the
+/// system font directories and among the embedded fonts.
+///
+/// The search runs once per process for the same arguments, so the font check
+/// before compilation and the world share it, including the fonts loaded from
+/// it so far. Nothing is kept on disk, every `mdbook build` and every rebuild
+/// of `mdbook serve` runs a new renderer process that searches again, only
+/// the recompilations of `watch = true` run in the same process.
+pub fn discover(args: &FontArgs) -> Arc<Fonts> {
+  let mut discovered = DISCOVERED.get_or_init(Default::default).lock();
+
+  discovered
+    .entry(args.clone())
+    .or_insert_with(|| Arc::new(search(args)))
+    .clone()
+}
+
+fn search(args: &FontArgs) -> Fonts {
+  let mut searcher = Fonts::searcher();
+
+  #[cfg(feature = "embed-fonts")]
+  searcher.include_embedded_fonts(!args.ignore_embedded_fonts);
+
+  searcher
+    .include_system_fonts(!args.ignore_system_fonts)
+    .search_with(&args.font_paths)
+}
+
+/// Prints the discovered font families, one per line in alphabetical order.
+pub fn list(book: &FontBook) {
+  for (name, _) in book.families() {
+    println!("{}", name);
+  }
+}
+
+/// Warns if the font family is not among the discovered fonts, listing the
+/// families with a similar name.
+pub fn check_family(book: &FontBook, family: &str) {
+  if book.contains_family(&family.to_lowercase()) {
+    return;
+  }
+
+  let needle = family.to_lowercase();
+  let words: Vec<&str> = needle.split_whitespace().filter(|w| w.len() > 2).collect();
+
+  let near_matches: Vec<&str> = book
+    .families()
+    .map(|(name, _)| name)
+    .filter(|name| {
+      let name = name.to_lowercase();
+      name.contains(&needle) || needle.contains(&name) || words.iter().any(|w| name.contains(w))
+    })
+    .take(5)
+    .collect();
+
+  if near_matches.is_empty() {
+    tracing::warn!("Font `{}` not found", family);
+  } else {
+    tracing::warn!(
+      "Font `{}` not found, similar fonts: {}",
+      family,
+      near_matches.join(", ")
+    );
+  }
+}
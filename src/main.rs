@@ -2,32 +2,274 @@ mod args;
 mod convert;
 mod download;
 mod export;
+mod fonts;
 mod package;
 mod terminal;
+mod watch;
 mod world;
 
-use args::{FontArgs, PackageStorageArgs};
+use args::{FontArgs, OutputFormat, PackageStorageArgs, Pages, PdfStandard};
+use chrono::{DateTime, Utc};
 use codespan_reporting::term::{self, termcolor};
 use mdbook::config::Config as MdConfig;
 use mdbook::renderer::RenderContext;
+use mdbook::BookItem;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs::File;
-use std::io::{self, IsTerminal, Write};
+use std::io::{self, BufWriter, IsTerminal, Write};
 use std::path::Path;
 use std::path::PathBuf;
-use tempfile::NamedTempFile;
 use termcolor::{ColorChoice, WriteColor};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use typst::syntax::Source;
 
 use crate::args::{Input, SharedArgs};
 
-#[derive(Debug, Default, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(default, rename_all = "kebab-case")]
 pub struct Config {
+  pub enabled: bool,
   pub pdf: bool,
+  pub convert_only: bool,
   pub custom_template: Option<String>,
   pub section_number: bool,
-  pub chapter_no_pagebreak: bool,
+  pub appendix_chapters: Vec<String>,
+  /// Deprecated, replaced by `chapter-pagebreak = false`.
+  pub chapter_no_pagebreak: Option<bool>,
+  pub chapter_pagebreak: bool,
+  pub paper: Option<String>,
+  pub orientation: Orientation,
+  pub margin: Margin,
+  pub font: Option<String>,
+  pub cjk_font: Option<String>,
+  pub font_fallback: Vec<String>,
+  pub toc: bool,
+  pub toc_depth: usize,
+  pub pages: Option<String>,
+  pub pdf_standard: Vec<PdfStandard>,
+  pub format: OutputFormat,
+  pub formats: Vec<OutputFormat>,
+  pub ppi: f32,
+  pub image_dpi: Option<f64>,
+  pub creation_timestamp: Option<i64>,
+  pub date: Option<String>,
+  pub date_format: Option<String>,
+  pub keywords: Vec<String>,
+  pub bibliography: Option<String>,
+  pub bibliography_style: Option<String>,
+  pub keep_typst: bool,
+  pub watch: bool,
+  pub jobs: Option<usize>,
+  pub font_paths: Vec<PathBuf>,
+  pub ignore_system_fonts: bool,
+  pub ignore_embedded_fonts: bool,
+  pub list_fonts: bool,
+  pub highlight_theme: Option<String>,
+  pub code_font: Option<String>,
+  pub code_font_size: Option<String>,
+  pub lang_aliases: HashMap<String, String>,
+  pub code_line_numbers: bool,
+  pub code_wrap: bool,
+  pub code_block_render: HashMap<String, String>,
+  pub passthrough_lang: Option<String>,
+  pub table: Table,
+  pub link_style: LinkStyle,
+  pub cover: Cover,
+  pub header: bool,
+  pub header_format: Option<String>,
+  pub footer: bool,
+  pub footer_format: Option<String>,
+  pub inputs: HashMap<String, String>,
+  pub package_path: Option<PathBuf>,
+  pub package_cache_path: Option<PathBuf>,
+  pub offline: bool,
+  pub remote_images: bool,
+  pub deny_warnings: bool,
+  pub smart_punctuation: bool,
+  pub front_matter: bool,
+  pub bare_urls: bool,
+  pub alerts: bool,
+  pub inline_footnotes: bool,
+  pub draft_chapters: DraftChapters,
+  pub empty_chapters: EmptyChapters,
+  pub front_matter_numbering: bool,
+  pub columns: usize,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Self {
+      enabled: true,
+      pdf: false,
+      convert_only: false,
+      custom_template: None,
+      section_number: false,
+      appendix_chapters: vec![],
+      chapter_no_pagebreak: None,
+      chapter_pagebreak: true,
+      paper: None,
+      orientation: Orientation::default(),
+      margin: Margin::default(),
+      font: None,
+      cjk_font: None,
+      font_fallback: vec![],
+      toc: true,
+      toc_depth: 2,
+      pages: None,
+      pdf_standard: vec![],
+      format: OutputFormat::default(),
+      formats: vec![],
+      ppi: 144.0,
+      image_dpi: None,
+      creation_timestamp: None,
+      date: None,
+      date_format: None,
+      keywords: vec![],
+      bibliography: None,
+      bibliography_style: None,
+      keep_typst: true,
+      watch: false,
+      jobs: None,
+      font_paths: vec![],
+      ignore_system_fonts: false,
+      ignore_embedded_fonts: false,
+      list_fonts: false,
+      highlight_theme: None,
+      code_font: None,
+      code_font_size: None,
+      lang_aliases: HashMap::new(),
+      code_line_numbers: false,
+      code_wrap: false,
+      code_block_render: HashMap::new(),
+      passthrough_lang: None,
+      table: Table::default(),
+      link_style: LinkStyle::default(),
+      cover: Cover::default(),
+      header: true,
+      header_format: None,
+      footer: true,
+      footer_format: None,
+      inputs: HashMap::new(),
+      package_path: None,
+      package_cache_path: None,
+      offline: false,
+      remote_images: true,
+      deny_warnings: false,
+      smart_punctuation: true,
+      front_matter: true,
+      bare_urls: true,
+      alerts: true,
+      inline_footnotes: false,
+      draft_chapters: DraftChapters::default(),
+      empty_chapters: EmptyChapters::default(),
+      front_matter_numbering: false,
+      columns: 1,
+    }
+  }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Margin {
+  pub top: Option<String>,
+  pub bottom: Option<String>,
+  pub left: Option<String>,
+  pub right: Option<String>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Table {
+  pub inset: Option<String>,
+  pub stroke: Option<String>,
+  pub header_fill: Option<String>,
+  pub columns: TableColumns,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct LinkStyle {
+  pub color: Option<String>,
+  pub underline: bool,
+  /// Links within the book, styled like links to urls unless set.
+  pub internal_color: Option<String>,
+  pub internal_underline: Option<bool>,
+}
+
+impl Default for LinkStyle {
+  fn default() -> Self {
+    Self {
+      color: None,
+      underline: true,
+      internal_color: None,
+      internal_underline: None,
+    }
+  }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct Cover {
+  pub subtitle: Option<String>,
+  pub author: Option<String>,
+  pub date: Option<String>,
+  pub image: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DraftChapters {
+  #[default]
+  Skip,
+  Placeholder,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EmptyChapters {
+  #[default]
+  Merge,
+  Skip,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TableColumns {
+  #[default]
+  Auto,
+  Equal,
+}
+
+impl Config {
+  /// The configured font families in fallback order.
+  pub fn font_families(&self) -> impl Iterator<Item = &String> {
+    self
+      .font
+      .iter()
+      .chain(self.cjk_font.iter())
+      .chain(self.font_fallback.iter())
+  }
+
+  /// The formats to export, `formats` if configured, otherwise `format`.
+  pub fn output_formats(&self) -> impl Iterator<Item = OutputFormat> + '_ {
+    let formats = if self.formats.is_empty() {
+      std::slice::from_ref(&self.format)
+    } else {
+      self.formats.as_slice()
+    };
+
+    formats.iter().copied()
+  }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Orientation {
+  #[default]
+  Portrait,
+  Landscape,
 }
 
 fn main() -> Result<(), anyhow::Error> {
@@ -36,54 +278,183 @@ fn main() -> Result<(), anyhow::Error> {
       tracing_subscriber::EnvFilter::try_from_default_env()
         .unwrap_or_else(|_| "mdbook_typst_pdf=info,typst=error".into()),
     )
-    .with(tracing_subscriber::fmt::layer())
+    .with(tracing_subscriber::fmt::layer().with_writer(terminal::log_writer))
     .init();
 
   let mut stdin = io::stdin();
 
   let ctx = RenderContext::from_json(&mut stdin)?;
 
-  let cfg: Config = ctx
+  let mut cfg: Config = ctx
     .config
     .get_deserialized_opt("output.typst-pdf")?
     .unwrap_or_default();
 
+  if let Some(no_pagebreak) = cfg.chapter_no_pagebreak.take() {
+    if ctx
+      .config
+      .get("output.typst-pdf.chapter-pagebreak")
+      .is_some()
+    {
+      tracing::warn!(
+        "Both `chapter-no-pagebreak` and `chapter-pagebreak` are set, ignoring the deprecated `chapter-no-pagebreak`"
+      );
+    } else {
+      tracing::warn!(
+        "`chapter-no-pagebreak` is deprecated, use `chapter-pagebreak = {}` instead",
+        !no_pagebreak
+      );
+
+      cfg.chapter_pagebreak = !no_pagebreak;
+    }
+  }
+
+  // The renderer stays in `book.toml` but writes nothing, e.g. for a build
+  // with `MDBOOK_OUTPUT__TYPST_PDF__ENABLED=false` that only wants HTML.
+  if !cfg.enabled {
+    tracing::info!("Renderer disabled by `enabled = false`, skipping");
+
+    return Ok(());
+  }
+
   let template_str = if let Some(custom_template) = &cfg.custom_template {
     let mut custom_template_path = ctx.root.clone();
     custom_template_path.push(custom_template);
-    std::fs::read_to_string(custom_template_path)?
+    let template_str = std::fs::read_to_string(custom_template_path)?;
+    check_template(&template_str, custom_template)?;
+    template_str
   } else {
     include_str!("assets/template.typ").to_string()
   };
 
-  let typst_str = convert::convert_typst(&ctx, &cfg, &template_str)?;
+  let pages = cfg
+    .pages
+    .as_ref()
+    .map(|pages| {
+      pages
+        .split(',')
+        .map(|p| p.parse::<Pages>())
+        .collect::<Result<Vec<Pages>, _>>()
+    })
+    .transpose()
+    .map_err(|err| {
+      anyhow::anyhow!(
+        "invalid pages `{}`: {}",
+        cfg.pages.as_deref().unwrap_or_default(),
+        err
+      )
+    })?;
+
+  let creation_timestamp = creation_timestamp(&cfg)?;
+
+  let font_args = FontArgs {
+    font_paths: cfg.font_paths.iter().map(|p| ctx.root.join(p)).collect(),
+    ignore_system_fonts: cfg.ignore_system_fonts,
+    ignore_embedded_fonts: cfg.ignore_embedded_fonts,
+  };
+
+  if cfg.list_fonts {
+    fonts::list(&fonts::discover(&font_args).book);
+
+    return Ok(());
+  }
+
+  if cfg.pdf
+    && !cfg.convert_only
+    && (cfg.font_families().next().is_some() || cfg.code_font.is_some())
+  {
+    let fonts = fonts::discover(&font_args);
+
+    for family in cfg.font_families().chain(cfg.code_font.iter()) {
+      fonts::check_family(&fonts.book, family);
+    }
+  }
+
+  // Both the conversion and the compilation run in the global thread pool.
+  if let Some(jobs) = cfg.jobs {
+    rayon::ThreadPoolBuilder::new()
+      .num_threads(jobs)
+      .use_current_thread()
+      .build_global()
+      .map_err(|err| anyhow::anyhow!("failed to configure thread pool ({err})"))?;
+  }
 
   let typst_filename = output_filename(&ctx.destination, &ctx.config, "typ");
 
-  if cfg.pdf {
-    let mut tmpfile = NamedTempFile::new()?;
-    tmpfile.write_all(typst_str.as_bytes())?;
-    tmpfile.flush()?;
+  let compile = cfg.pdf && !cfg.convert_only;
+
+  // Without `keep-typst` the typst file is compiled from a temporary file
+  // next to the output, so relative image paths still resolve.
+  let mut tmpfile = None;
+
+  let input = if compile && !cfg.keep_typst {
+    std::fs::create_dir_all(&ctx.destination)?;
 
-    write_file(&typst_str, &typst_filename);
+    let file = tempfile::Builder::new()
+      .suffix(".typ")
+      .tempfile_in(&ctx.destination)?;
 
+    tmpfile.insert(file).path().to_path_buf()
+  } else {
+    typst_filename.clone()
+  };
+
+  let file = File::create(&input)
+    .map_err(|err| anyhow::anyhow!("failed to create `{}`: {}", input.display(), err))?;
+
+  let source_map = convert::convert_typst(&ctx, &cfg, &template_str, BufWriter::new(file))?;
+
+  if cfg.convert_only {
+    let chapters = ctx
+      .book
+      .iter()
+      .filter(|item| matches!(item, BookItem::Chapter(ch) if ch.source_path.is_some()))
+      .count();
+
+    tracing::info!(
+      "Converted {} chapters to `{}`, skipping compilation",
+      chapters,
+      typst_filename.display()
+    );
+  } else if compile {
     let args = SharedArgs {
-      input: Input::Path(typst_filename),
-      inputs: vec![],
-      output: output_filename(&ctx.destination, &ctx.config, "pdf"),
-      root: None,
-      font_args: FontArgs {
-        font_paths: vec![],
-        ignore_system_fonts: false,
-      },
-      creation_timestamp: None,
+      input: Input::Path(input),
+      inputs: cfg
+        .inputs
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect(),
+      outputs: cfg
+        .output_formats()
+        .map(|format| {
+          let output = match format {
+            OutputFormat::Pdf => output_filename(&ctx.destination, &ctx.config, "pdf"),
+            format => page_output_filename(&ctx.destination, &ctx.config, format.extension()),
+          };
+
+          (format, output)
+        })
+        .collect(),
+      root: Some(ctx.destination.clone()),
+      font_args,
+      creation_timestamp,
       package_storage_args: PackageStorageArgs {
-        package_cache_path: None,
-        package_path: None,
+        package_cache_path: cfg.package_cache_path.as_ref().map(|p| ctx.root.join(p)),
+        package_path: cfg.package_path.as_ref().map(|p| ctx.root.join(p)),
+        offline: cfg.offline,
       },
+      pages,
+      pdf_standard: cfg.pdf_standard.clone(),
+      ppi: cfg.ppi,
+      deny_warnings: cfg.deny_warnings,
+      source_map,
     };
 
-    let res = crate::export::export_pdf(args);
+    if cfg.watch {
+      return watch::watch(&ctx, &cfg, &template_str, args);
+    }
+
+    let res = crate::export::export(args);
 
     if let Err(msg) = res {
       print_error(&msg).expect("failed to print error");
@@ -95,6 +466,78 @@ fn main() -> Result<(), anyhow::Error> {
   Ok(())
 }
 
+/// The document's creation date, from the `creation-timestamp` option or the
+/// `SOURCE_DATE_EPOCH` environment variable.
+fn creation_timestamp(cfg: &Config) -> Result<Option<DateTime<Utc>>, anyhow::Error> {
+  let timestamp = match cfg.creation_timestamp {
+    Some(timestamp) => timestamp,
+    None => match std::env::var("SOURCE_DATE_EPOCH") {
+      Ok(raw) => raw
+        .parse()
+        .map_err(|err| anyhow::anyhow!("invalid SOURCE_DATE_EPOCH `{}`: {}", raw, err))?,
+      Err(_) => return Ok(None),
+    },
+  };
+
+  DateTime::from_timestamp(timestamp, 0)
+    .map(Some)
+    .ok_or(anyhow::anyhow!("timestamp out of range: {}", timestamp))
+}
+
+/// Fail early on custom templates with typst syntax errors or without the
+/// content placeholder, instead of after converting the whole book.
+fn check_template(template: &str, name: &str) -> Result<(), anyhow::Error> {
+  if !template.contains(convert::BODY_PLACEHOLDER)
+    && !template.contains(convert::CONTENT_PLACEHOLDER)
+  {
+    return Err(anyhow::anyhow!(
+      "template `{}` has no `{}`, add it on its own line where the chapters should go, e.g. after the table of contents",
+      name,
+      convert::BODY_PLACEHOLDER
+    ));
+  }
+
+  // Placeholders are checked as `none`, except the font list which expands to
+  // comma terminated array items.
+  let placeholder_regex = Regex::new(r"MDBOOK_TYPST_PDF_[A-Z_]+").unwrap();
+
+  let text =
+    placeholder_regex.replace_all(template, |captures: &regex::Captures| match &captures[0] {
+      "MDBOOK_TYPST_PDF_FONT" => "",
+      _ => "none",
+    });
+
+  let source = Source::detached(text);
+
+  let errors = source.root().errors();
+
+  let Some(error) = errors.first() else {
+    return Ok(());
+  };
+
+  let position = source.range(error.span).and_then(|range| {
+    Some((
+      source.byte_to_line(range.start)? + 1,
+      source.byte_to_column(range.start)? + 1,
+    ))
+  });
+
+  match position {
+    Some((line, column)) => Err(anyhow::anyhow!(
+      "syntax error in template `{}` at {}:{}: {}",
+      name,
+      line,
+      column,
+      error.message
+    )),
+    None => Err(anyhow::anyhow!(
+      "syntax error in template `{}`: {}",
+      name,
+      error.message
+    )),
+  }
+}
+
 fn color_stream() -> termcolor::StandardStream {
   termcolor::StandardStream::stderr(if std::io::stderr().is_terminal() {
     ColorChoice::Auto
@@ -114,22 +557,19 @@ fn print_error(msg: &str) -> io::Result<()> {
   writeln!(w, ": {msg}.")
 }
 
-fn write_file(data: &str, filename: &PathBuf) {
-  let display = filename.display();
-
-  let mut file = match File::create(filename) {
-    Err(why) => panic!("Couldn't create {}: {}", display, why),
-    Ok(file) => file,
-  };
-
-  if let Err(why) = file.write_all(data.as_bytes()) {
-    panic!("Couldn't write to {}: {}", display, why)
-  }
-}
-
 fn output_filename(dest: &Path, config: &MdConfig, extension: &str) -> PathBuf {
   match config.book.title {
     Some(ref title) => dest.join(title).with_extension(extension),
     None => dest.join("book").with_extension(extension),
   }
 }
+
+/// Output filename with a page number template, for formats writing one file
+/// per page.
+fn page_output_filename(dest: &Path, config: &MdConfig, extension: &str) -> PathBuf {
+  let title = config.book.title.as_deref().unwrap_or("book");
+
+  dest
+    .join(format!("{}-{{0p}}", title))
+    .with_extension(extension)
+}
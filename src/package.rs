@@ -1,13 +1,57 @@
+use std::path::PathBuf;
+
+use ecow::eco_format;
+use typst::diag::{PackageError, PackageResult};
+use typst::syntax::package::PackageSpec;
+use typst_kit::download::Progress;
 use typst_kit::package::PackageStorage;
 
 use crate::args::PackageStorageArgs;
 use crate::download;
 
+/// Package storage that only resolves packages already on disk when offline.
+pub struct Storage {
+  storage: PackageStorage,
+  offline: bool,
+}
+
+impl Storage {
+  /// Make a package available on disk, downloading it unless offline.
+  pub fn prepare_package(
+    &self,
+    spec: &PackageSpec,
+    progress: &mut dyn Progress,
+  ) -> PackageResult<PathBuf> {
+    if !self.offline {
+      return self.storage.prepare_package(spec, progress);
+    }
+
+    let subdir = format!("{}/{}/{}", spec.namespace, spec.name, spec.version);
+
+    self
+      .storage
+      .package_path()
+      .into_iter()
+      .chain(self.storage.package_cache_path())
+      .map(|dir| dir.join(&subdir))
+      .find(|dir| dir.exists())
+      .ok_or_else(|| {
+        PackageError::Other(Some(eco_format!(
+          "package {} not found locally and offline is enabled",
+          spec
+        )))
+      })
+  }
+}
+
 /// Returns a new package storage for the given args.
-pub fn storage(args: &PackageStorageArgs) -> PackageStorage {
-  PackageStorage::new(
-    args.package_cache_path.clone(),
-    args.package_path.clone(),
-    download::downloader(),
-  )
+pub fn storage(args: &PackageStorageArgs) -> Storage {
+  Storage {
+    storage: PackageStorage::new(
+      args.package_cache_path.clone(),
+      args.package_path.clone(),
+      download::downloader(),
+    ),
+    offline: args.offline,
+  }
 }
@@ -1,8 +1,18 @@
 use codespan_reporting::term::termcolor;
-use std::io::{self, Write};
+use std::fmt::Display;
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 use termcolor::{ColorChoice, WriteColor};
 use typst::utils::singleton;
 
+/// How often a status is logged when stderr is not a terminal.
+const STATUS_LOG_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Frames of the spinner shown by [`spin`].
+const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+
 /// Returns a handle to the optionally colored terminal output.
 pub fn out() -> TermOut {
   TermOut {
@@ -13,6 +23,8 @@ pub fn out() -> TermOut {
 /// The stuff that has to be shared between instances of [`TermOut`].
 struct TermOutInner {
   stream: termcolor::StandardStream,
+  /// Whether the last line written is a status line.
+  status: AtomicBool,
 }
 
 impl TermOutInner {
@@ -20,7 +32,10 @@ impl TermOutInner {
     let color_choice = ColorChoice::Auto;
 
     let stream = termcolor::StandardStream::stderr(color_choice);
-    TermOutInner { stream }
+    TermOutInner {
+      stream,
+      status: AtomicBool::new(false),
+    }
   }
 }
 
@@ -45,6 +60,100 @@ impl TermOut {
     }
     Ok(())
   }
+
+  /// Shows a status line, replacing the previous status line.
+  pub fn set_status(&mut self, status: &str) -> io::Result<()> {
+    self.clear_status()?;
+    writeln!(self, "{status}")?;
+    self.inner.status.store(true, Ordering::Relaxed);
+    Ok(())
+  }
+
+  /// Clears the status line if it is the last line written.
+  pub fn clear_status(&mut self) -> io::Result<()> {
+    if self.inner.status.swap(false, Ordering::Relaxed) {
+      self.clear_last_line()?;
+    }
+    Ok(())
+  }
+}
+
+/// Returns the writer for log lines, which replace a shown status line.
+pub fn log_writer() -> io::Stdout {
+  let _ = out().clear_status();
+  io::stdout()
+}
+
+/// Reports the progress of a long running step. On a terminal the status line
+/// is updated in place, otherwise the status is logged every few seconds.
+pub struct Status {
+  interactive: bool,
+  last_log: Instant,
+}
+
+impl Status {
+  pub fn new() -> Self {
+    Status {
+      interactive: io::stderr().is_terminal() && out().supports_color(),
+      last_log: Instant::now(),
+    }
+  }
+
+  /// Whether the status is updated in place.
+  pub fn is_interactive(&self) -> bool {
+    self.interactive
+  }
+
+  /// Reports the current status.
+  pub fn update(&mut self, status: impl Display) {
+    if self.interactive {
+      let _ = out().set_status(&status.to_string());
+    } else if self.last_log.elapsed() >= STATUS_LOG_INTERVAL {
+      tracing::info!("{status}");
+      self.last_log = Instant::now();
+    }
+  }
+
+  /// Removes the status line.
+  pub fn finish(&mut self) {
+    if self.interactive {
+      let _ = out().clear_status();
+    }
+  }
+}
+
+/// Runs `f` while showing a spinner with the elapsed time.
+pub fn spin<T>(message: &str, f: impl FnOnce() -> T) -> T {
+  let done = AtomicBool::new(false);
+
+  thread::scope(|scope| {
+    scope.spawn(|| {
+      let mut status = Status::new();
+      let start = Instant::now();
+
+      for frame in SPINNER.iter().cycle() {
+        if done.load(Ordering::Relaxed) {
+          break;
+        }
+
+        let elapsed = start.elapsed().as_secs();
+
+        if status.is_interactive() {
+          status.update(format_args!("{frame} {message} ({elapsed}s)"));
+        } else {
+          status.update(format_args!("Still {message} ({elapsed}s)"));
+        }
+
+        thread::sleep(Duration::from_millis(100));
+      }
+
+      status.finish();
+    });
+
+    let result = f();
+    done.store(true, Ordering::Relaxed);
+    result
+  })
 }
 
 impl Write for TermOut {
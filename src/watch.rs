@@ -0,0 +1,117 @@
+use crate::args::{Input, SharedArgs};
+use crate::convert::{self, SourceMap};
+use crate::{export, terminal, Config};
+use mdbook::renderer::{RenderContext, Renderer};
+use mdbook::MDBook;
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+
+/// How long to wait for further changes before rebuilding, so saving several
+/// files at once triggers a single rebuild.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// Stands in for this renderer when the book is preprocessed again, so
+/// preprocessors that only support some renderers behave as in `mdbook build`.
+struct TypstPdf;
+
+impl Renderer for TypstPdf {
+  fn name(&self) -> &str {
+    "typst-pdf"
+  }
+
+  fn render(&self, _ctx: &RenderContext) -> mdbook::errors::Result<()> {
+    Ok(())
+  }
+}
+
+/// Compile the book, then convert and recompile it whenever a file in the
+/// book's source directory changes, until the process is stopped.
+///
+/// The world is kept between compilations, so unchanged files are not read
+/// again and typst reuses its incremental caches.
+pub fn watch(
+  ctx: &RenderContext,
+  cfg: &Config,
+  template_str: &str,
+  mut args: SharedArgs,
+) -> Result<(), anyhow::Error> {
+  let Input::Path(input) = args.input.clone() else {
+    unreachable!("the book is always compiled from a file");
+  };
+
+  let mut world = export::setup(&args).map_err(|err| anyhow::anyhow!(err))?;
+  let src = ctx.root.join(&ctx.config.book.src);
+
+  let (tx, rx) = mpsc::channel();
+  let mut watcher = notify::recommended_watcher(tx)?;
+  watcher.watch(&src, RecursiveMode::Recursive)?;
+
+  let mut start = Instant::now();
+  let mut result = export::compile(&world, &args).map_err(|err| anyhow::anyhow!(err));
+
+  loop {
+    let status = match result {
+      Ok(()) => format!("Built in {:.2?}", start.elapsed()),
+      Err(err) => {
+        crate::print_error(&format!("{err:#}"))?;
+
+        "Build failed".to_string()
+      }
+    };
+    terminal::out().set_status(&format!(
+      "{}, watching `{}` for changes",
+      status,
+      src.display()
+    ))?;
+
+    wait_for_change(&rx)?;
+
+    terminal::out().set_status("Rebuilding...")?;
+    start = Instant::now();
+    result = convert_book(ctx, cfg, template_str, &input).and_then(|source_map| {
+      args.source_map = source_map;
+      world.reset();
+
+      export::compile(&world, &args).map_err(|err| anyhow::anyhow!(err))
+    });
+
+    comemo::evict(10);
+  }
+}
+
+/// Block until a file changes, then wait for the changes following it.
+fn wait_for_change(
+  rx: &mpsc::Receiver<notify::Result<notify::Event>>,
+) -> Result<(), anyhow::Error> {
+  loop {
+    let event = rx.recv()??;
+
+    if !matches!(event.kind, EventKind::Access(_)) {
+      break;
+    }
+  }
+
+  while rx.recv_timeout(DEBOUNCE).is_ok() {}
+
+  Ok(())
+}
+
+/// Load and preprocess the book again and write its conversion to `input`.
+fn convert_book(
+  ctx: &RenderContext,
+  cfg: &Config,
+  template_str: &str,
+  input: &Path,
+) -> Result<SourceMap, anyhow::Error> {
+  let md = MDBook::load(&ctx.root)?;
+  let (book, _) = md.preprocess_book(&TypstPdf)?;
+  let ctx = RenderContext::new(&ctx.root, book, ctx.config.clone(), &ctx.destination);
+
+  let file = File::create(input)?;
+
+  convert::convert_typst(&ctx, cfg, template_str, BufWriter::new(file))
+}
@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::sync::OnceLock;
+use std::sync::{Arc, OnceLock};
 use std::{fmt, fs, io, mem};
 
 use chrono::{DateTime, Datelike, FixedOffset, Local, Utc};
@@ -14,13 +14,12 @@ use typst::syntax::{FileId, Source, VirtualPath};
 use typst::text::{Font, FontBook};
 use typst::utils::LazyHash;
 use typst::{Library, World};
-use typst_kit::fonts::{FontSlot, Fonts};
-use typst_kit::package::PackageStorage;
+use typst_kit::fonts::Fonts;
 use typst_timing::{timed, TimingScope};
 
 use crate::args::{Input, SharedArgs};
 use crate::download::PrintDownload;
-use crate::package;
+use crate::{fonts, package};
 
 /// Static `FileId` allocated for stdin.
 /// This is to ensure that a file is read in the correct way.
@@ -38,12 +37,13 @@ pub struct SystemWorld {
   library: LazyHash<Library>,
   /// Metadata about discovered fonts.
   book: LazyHash<FontBook>,
-  /// Locations of and storage for lazily loaded fonts.
-  fonts: Vec<FontSlot>,
+  /// Locations of and storage for lazily loaded fonts, shared with other
+  /// worlds discovering fonts with the same arguments.
+  fonts: Arc<Fonts>,
   /// Maps file ids to source files and buffers.
   slots: Mutex<HashMap<FileId, FileSlot>>,
   /// Holds information about where packages are stored.
-  package_storage: PackageStorage,
+  package_storage: package::Storage,
   /// The current datetime if requested. This is stored here to ensure it is
   /// always the same within one compilation.
   /// Reset between compilations if not [`Now::Fixed`].
@@ -96,9 +96,7 @@ impl SystemWorld {
       Library::builder().with_inputs(inputs).build()
     };
 
-    let fonts = Fonts::searcher()
-      .include_system_fonts(!command.font_args.ignore_system_fonts)
-      .search_with(&command.font_args.font_paths);
+    let fonts = fonts::discover(&command.font_args);
 
     let now = match command.creation_timestamp {
       Some(time) => Now::Fixed(time),
@@ -110,8 +108,8 @@ impl SystemWorld {
       root,
       main,
       library: LazyHash::new(library),
-      book: LazyHash::new(fonts.book),
-      fonts: fonts.fonts,
+      book: LazyHash::new(fonts.book.clone()),
+      fonts,
       slots: Mutex::new(HashMap::new()),
       package_storage: package::storage(&command.package_storage_args),
       now,
@@ -164,7 +162,7 @@ impl World for SystemWorld {
   }
 
   fn font(&self, index: usize) -> Option<Font> {
-    self.fonts[index].get()
+    self.fonts.fonts[index].get()
   }
 
   fn today(&self, offset: Option<i64>) -> Option<Datetime> {
@@ -191,6 +189,16 @@ impl World for SystemWorld {
 }
 
 impl SystemWorld {
+  /// Reset the compilation state in preparation of a new compilation.
+  pub fn reset(&mut self) {
+    for slot in self.slots.get_mut().values_mut() {
+      slot.reset();
+    }
+    if let Now::System(time_lock) = &mut self.now {
+      time_lock.take();
+    }
+  }
+
   /// Access the canonical slot for the given file id.
   fn slot<F, T>(&self, id: FileId, f: F) -> T
   where
@@ -223,11 +231,18 @@ impl FileSlot {
     }
   }
 
+  /// Marks the file as not yet accessed in preparation of the next
+  /// compilation.
+  fn reset(&mut self) {
+    self.source.reset();
+    self.file.reset();
+  }
+
   /// Retrieve the source for this file.
   fn source(
     &mut self,
     project_root: &Path,
-    package_storage: &PackageStorage,
+    package_storage: &package::Storage,
   ) -> FileResult<Source> {
     self.source.get_or_init(
       || read(self.id, project_root, package_storage),
@@ -250,7 +265,7 @@ impl FileSlot {
   }
 
   /// Retrieve the file's bytes.
-  fn file(&mut self, project_root: &Path, package_storage: &PackageStorage) -> FileResult<Bytes> {
+  fn file(&mut self, project_root: &Path, package_storage: &package::Storage) -> FileResult<Bytes> {
     self.file.get_or_init(
       || read(self.id, project_root, package_storage),
       |data, _| Ok(data.into()),
@@ -278,6 +293,12 @@ impl<T: Clone> SlotCell<T> {
     }
   }
 
+  /// Marks the cell as not yet accessed in preparation of the next
+  /// compilation.
+  fn reset(&mut self) {
+    self.accessed = false;
+  }
+
   /// Gets the contents of the cell or initialize them.
   fn get_or_init(
     &mut self,
@@ -315,7 +336,7 @@ impl<T: Clone> SlotCell<T> {
 fn system_path(
   project_root: &Path,
   id: FileId,
-  package_storage: &PackageStorage,
+  package_storage: &package::Storage,
 ) -> FileResult<PathBuf> {
   // Determine the root path relative to which the file path
   // will be resolved.
@@ -335,7 +356,11 @@ fn system_path(
 ///
 /// If the ID represents stdin it will read from standard input,
 /// otherwise it gets the file path of the ID and reads the file from disk.
-fn read(id: FileId, project_root: &Path, package_storage: &PackageStorage) -> FileResult<Vec<u8>> {
+fn read(
+  id: FileId,
+  project_root: &Path,
+  package_storage: &package::Storage,
+) -> FileResult<Vec<u8>> {
   if id == *STDIN_ID {
     read_from_stdin()
   } else {